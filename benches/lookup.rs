@@ -0,0 +1,76 @@
+//! Replay lookup time versus Db size.
+//!
+//! cargo bench --bench lookup
+
+#![allow(dead_code)]
+
+use actix_web::http::{Method, StatusCode, header::HeaderMap};
+use bytes::Bytes;
+use std::{hint::black_box, time::Instant};
+
+#[path = "../src/matcher.rs"]
+mod matcher;
+#[path = "../src/ngrams.rs"]
+mod ngrams;
+#[path = "../src/sql.rs"]
+mod sql;
+
+use matcher::NgramsMatcher;
+use ngrams::{Db, Dbly, MiddlewareData, MiddlewareDataHttp, MiddlewareDataRequest};
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+const LOOKUPS: usize = 1_000;
+
+fn query(i: usize) -> String {
+    format!(
+        "SELECT col_{}, count() FROM db_{}.table_{} WHERE id = {} AND name = 'user_{}' GROUP BY col_{}",
+        i % 50, i % 7, i, i, i % 1000, i % 50,
+    )
+}
+
+fn meta() -> MiddlewareDataRequest {
+    MiddlewareDataRequest::new(Method::POST, "/".to_string(), Vec::new(), &HeaderMap::new(), Bytes::new())
+}
+
+fn fill(size: usize) -> Db {
+    let mut db = Db::new();
+    for i in 0..size {
+        db.push(MiddlewareData::new(
+            query(i),
+            Some(meta()),
+            Bytes::from(format!("{}\n", i)),
+            Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new())),
+        ));
+    }
+    db
+}
+
+fn bench(db: &mut Db, name: &str, req: impl Fn(usize) -> String, mask_literals: bool) {
+    let size = db.len();
+    let matcher = NgramsMatcher::default();
+    let requests = (0..LOOKUPS)
+        .map(|i| MiddlewareData::new(req(i * size / LOOKUPS), Some(meta()), Bytes::new(), None))
+        .collect::<Vec<MiddlewareData>>();
+
+    let start = Instant::now();
+    for req in requests.iter() {
+        black_box(db.find_best_response(req, &matcher, mask_literals));
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:>7} entries  {:<28} {:>10.1} us/lookup",
+        size,
+        name,
+        elapsed.as_secs_f64() * 1e6 / LOOKUPS as f64,
+    );
+}
+
+fn main() {
+    for size in SIZES {
+        let mut db = fill(size);
+        bench(&mut db, "exact", query, false);
+        bench(&mut db, "fuzzy", |i| format!("{} LIMIT 10", query(i)), false);
+        bench(&mut db, "fuzzy, masked literals", |i| format!("{} LIMIT 10", query(i)), true);
+    }
+}
@@ -0,0 +1,442 @@
+use actix_web::{
+    error::{InternalError, JsonPayloadError, PathError, QueryPayloadError},
+    http::{header::HeaderMap, StatusCode},
+    web, App, HttpRequest, HttpResponse, HttpServer, ResponseError,
+};
+use log::info;
+use serde_json::{json, Value};
+use std::{collections::HashMap, fmt, time::Duration};
+use tokio::{io, sync::mpsc::{self, Sender}};
+
+use crate::{
+    appguts::{AppGuts, Protocol, State},
+    edit::{self, ResponseEdit},
+    listing::{self, Listing},
+    ngrams::MiddlewareData,
+};
+
+/// HTTP admin API with JSON requests and responses, see README for the endpoints.
+/// Returns once it was asked to stop.
+pub async fn start_admin_handler(port: &str, guts: AppGuts) -> io::Result<()> {
+    let (stop_sender, mut stop_receiver) = mpsc::channel::<()>(1);
+
+    let addr = format!("localhost:{}", &port);
+    info!("Starting admin API at http://{}/__admin", addr);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(guts.clone()))
+            .app_data(web::Data::new(stop_sender.clone()))
+            .app_data(web::JsonConfig::default().error_handler(|e: JsonPayloadError, _| bad_request(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e: QueryPayloadError, _| bad_request(e)))
+            .app_data(web::PathConfig::default().error_handler(|e: PathError, _| bad_request(e)))
+            .service(
+                web::scope("/__admin")
+                    .route("/state", web::get().to(get_state))
+                    .route("/state", web::put().to(set_state))
+                    .route("/recordings", web::get().to(recordings))
+                    .route("/recordings", web::post().to(insert_recording))
+                    .route("/recordings", web::delete().to(delete_recordings))
+                    .route("/recordings/{index}", web::get().to(recording))
+                    .route("/recordings/{index}", web::patch().to(edit_recording))
+                    .route("/recordings/{index}", web::delete().to(delete_recording))
+                    .route("/recordings/{index}/move", web::post().to(move_recording))
+                    .route("/stats", web::get().to(stats))
+                    .route("/compare", web::get().to(compare))
+                    .route("/save", web::post().to(save))
+                    .route("/load", web::post().to(load))
+                    .route("/rewind", web::post().to(rewind))
+                    .route("/reset", web::post().to(reset))
+                    .route("/stop", web::post().to(stop)),
+            )
+            .default_service(web::to(not_found))
+    })
+    .bind(addr)?
+    .workers(1)
+    .shutdown_timeout(5)
+    .run();
+
+    // The stop request is answered before the server goes down.
+    let handle = server.handle();
+    tokio::spawn(async move {
+        if stop_receiver.recv().await.is_some() {
+            handle.stop(true).await;
+        }
+    });
+
+    server.await
+}
+
+/// Error answered as `{"error": message}`.
+#[derive(Debug)]
+struct AdminError {
+    status: StatusCode,
+    message: String,
+}
+
+impl AdminError {
+    fn new(status: StatusCode, message: impl fmt::Display) -> Self {
+        Self { status, message: message.to_string() }
+    }
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json!({ "error": self.message }))
+    }
+}
+
+type AdminResult = Result<HttpResponse, AdminError>;
+
+fn bad_request(e: impl fmt::Display) -> actix_web::Error {
+    InternalError::from_response("", AdminError::new(StatusCode::BAD_REQUEST, e).error_response()).into()
+}
+
+/// String field of a JSON request body.
+fn string_field<'a>(body: &'a Value, key: &str) -> Result<&'a str, AdminError> {
+    body.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| AdminError::new(StatusCode::BAD_REQUEST, format!("expected a JSON object with a string {:?} field", key)))
+}
+
+/// Fields of a JSON object as text command options, every `headers` entry becomes a `header="name: value"` option
+/// and a null header value removes the header.
+fn json_options(body: &Value) -> Result<Vec<(String, String)>, AdminError> {
+    let object = body.as_object()
+        .ok_or_else(|| AdminError::new(StatusCode::BAD_REQUEST, "expected a JSON object"))?;
+    let mut options = Vec::new();
+    for (key, value) in object {
+        match (key.as_str(), value) {
+            ("headers", Value::Object(headers)) => {
+                for (name, value) in headers {
+                    let value = match value {
+                        Value::String(value) => value.as_str(),
+                        Value::Null => "",
+                        _ => return Err(AdminError::new(StatusCode::BAD_REQUEST, format!("expected a string or null for header {:?}", name))),
+                    };
+                    options.push(("header".to_string(), format!("{}: {}", name, value)));
+                }
+            }
+            (_, Value::String(value)) => options.push((key.clone(), value.clone())),
+            (_, Value::Number(value)) => options.push((key.clone(), value.to_string())),
+            _ => return Err(AdminError::new(StatusCode::BAD_REQUEST, format!("unexpected value for {:?}", key))),
+        }
+    }
+    Ok(options)
+}
+
+fn summary(idx: usize, data: &MiddlewareData) -> Value {
+    let meta = data.request_meta();
+    json!({
+        "index": idx,
+        "protocol": if data.native().is_some() { "native" } else { "http" },
+        "query": data.request(),
+        "method": meta.map(|meta| meta.method().as_str()),
+        "path": meta.map(|meta| meta.path()),
+        "status": data.http().map(|http| http.split().0.as_u16()),
+        "response_size": data.response().len(),
+        "first_byte_ms": data.timing().map(|timing| millis(timing.first_byte)),
+        "total_ms": data.timing().map(|timing| millis(timing.total)),
+        "recorded_at": data.recorded_at().map(listing::format_time),
+    })
+}
+
+/// Summary with the request parameters and headers, the response headers and the response itself.
+fn details(idx: usize, data: &MiddlewareData) -> Value {
+    let headers = |headers: &HeaderMap| headers.iter()
+        .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+        .collect::<Vec<_>>();
+    let mut details = summary(idx, data);
+    details["params"] = json!(data.request_meta().map(|meta| meta.query()));
+    details["request_headers"] = json!(data.request_meta().map(|meta| headers(meta.headers())));
+    details["response_headers"] = json!(data.http().map(|http| headers(&http.split().1)));
+    details["chunks"] = json!(data.http().map(|http| http.chunks().len()));
+    details["native_revision"] = json!(data.native().map(|native| native.revision));
+    details["response"] = json!(listing::response_text(data));
+    details
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+fn state_json(guts: &AppGuts) -> Value {
+    let guts = guts.lock().unwrap();
+    json!({
+        "http": guts.state(Protocol::Http).to_string(),
+        "native": guts.state(Protocol::Native).to_string(),
+        "recordings": guts.entries().count(),
+        "new_recordings": guts.new_recordings(),
+    })
+}
+
+async fn get_state(guts: web::Data<AppGuts>) -> AdminResult {
+    Ok(HttpResponse::Ok().json(state_json(&guts)))
+}
+
+/// `{"state": ..., "protocol": "http" | "native"}`, both protocols are set without a protocol.
+async fn set_state(guts: web::Data<AppGuts>, body: web::Json<Value>) -> AdminResult {
+    let state: State = string_field(&body, "state")?
+        .parse()
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?;
+    let protocol: Option<Protocol> = match body.get("protocol") {
+        None | Some(Value::Null) => None,
+        Some(_) => Some(string_field(&body, "protocol")?
+            .parse()
+            .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?),
+    };
+    guts.lock().unwrap().set_state(protocol, state)
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?;
+    Ok(HttpResponse::Ok().json(state_json(&guts)))
+}
+
+/// Recordings matching the filter parameters, see `Listing::from_options`, a page at a time.
+async fn recordings(guts: web::Data<AppGuts>, params: web::Query<Vec<(String, String)>>) -> AdminResult {
+    let listing = Listing::from_options(params.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?;
+    let guts = guts.lock().unwrap();
+    let (page, total) = listing.page(guts.entries());
+    let entries = page.into_iter()
+        .map(|(idx, data)| summary(idx, data))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({
+        "total": total,
+        "offset": listing.offset,
+        "recordings": entries,
+    })))
+}
+
+async fn recording(guts: web::Data<AppGuts>, idx: web::Path<usize>) -> AdminResult {
+    let idx = idx.into_inner();
+    let guts = guts.lock().unwrap();
+    let data = guts.entry(idx)
+        .ok_or_else(|| AdminError::new(StatusCode::NOT_FOUND, format!("no recording {}", idx)))?;
+    Ok(HttpResponse::Ok().json(details(idx, data)))
+}
+
+/// Hand-written HTTP recording, see `edit::handwritten_entry` for the fields and `headers` as an object.
+async fn insert_recording(guts: web::Data<AppGuts>, body: web::Json<Value>) -> AdminResult {
+    let options = json_options(&body)?;
+    let (idx, data) = edit::handwritten_entry(options.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?;
+    let mut guts = guts.lock().unwrap();
+    let idx = guts.insert_entry(idx, data);
+    Ok(HttpResponse::Created().json(details(idx, guts.entry(idx).unwrap())))
+}
+
+/// `{"status": CODE, "body": TEXT, "headers": {"name": "value" | null}}`, every field is optional.
+async fn edit_recording(guts: web::Data<AppGuts>, idx: web::Path<usize>, body: web::Json<Value>) -> AdminResult {
+    let idx = idx.into_inner();
+    let mut edit = ResponseEdit::default();
+    for (key, value) in json_options(&body)? {
+        edit.set(&key, &value).map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+    let mut guts = guts.lock().unwrap();
+    if guts.entry(idx).is_none() {
+        return Err(AdminError::new(StatusCode::NOT_FOUND, format!("no recording {}", idx)));
+    }
+    guts.edit_entry(idx, &edit).map_err(|e| AdminError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(HttpResponse::Ok().json(details(idx, guts.entry(idx).unwrap())))
+}
+
+async fn delete_recording(guts: web::Data<AppGuts>, idx: web::Path<usize>) -> AdminResult {
+    let idx = idx.into_inner();
+    guts.lock().unwrap().delete_entry(idx)
+        .ok_or_else(|| AdminError::new(StatusCode::NOT_FOUND, format!("no recording {}", idx)))?;
+    Ok(HttpResponse::Ok().json(json!({ "deleted": 1 })))
+}
+
+/// Deletes the recordings matching the filter parameters of `recordings`, `reset` deletes everything.
+async fn delete_recordings(guts: web::Data<AppGuts>, params: web::Query<Vec<(String, String)>>) -> AdminResult {
+    let filter = Listing::from_options(params.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e))?
+        .filter;
+    if filter.is_empty() {
+        return Err(AdminError::new(StatusCode::BAD_REQUEST, "expected filter parameters, use reset to delete everything"));
+    }
+    let deleted = guts.lock().unwrap().delete_entries(&filter);
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted })))
+}
+
+/// `{"to": INDEX}`
+async fn move_recording(guts: web::Data<AppGuts>, idx: web::Path<usize>, body: web::Json<Value>) -> AdminResult {
+    let idx = idx.into_inner();
+    let to = body.get("to")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| AdminError::new(StatusCode::BAD_REQUEST, "expected a JSON object with an integer \"to\" field"))?;
+    let mut guts = guts.lock().unwrap();
+    guts.move_entry(idx, to as usize).map_err(|e| AdminError::new(StatusCode::NOT_FOUND, e))?;
+    Ok(HttpResponse::Ok().json(summary(to as usize, guts.entry(to as usize).unwrap())))
+}
+
+async fn stats(guts: web::Data<AppGuts>) -> AdminResult {
+    let guts = guts.lock().unwrap();
+    let stats = guts.lookup_stats();
+    Ok(HttpResponse::Ok().json(json!({
+        "lookups": stats.lookups,
+        "fast_path_hits": stats.fast_path_hits,
+    })))
+}
+
+/// Best recording and its score for every matching strategy, `?query=...`.
+async fn compare(guts: web::Data<AppGuts>, params: web::Query<HashMap<String, String>>) -> AdminResult {
+    let query = params.get("query")
+        .ok_or_else(|| AdminError::new(StatusCode::BAD_REQUEST, "expected a query parameter"))?;
+    let guts = guts.lock().unwrap();
+    let scores = guts.compare_matchers(query.to_string()).into_iter()
+        .map(|(name, best)| json!({
+            "matcher": name,
+            "index": best.map(|(idx, _)| idx),
+            "score": best.map(|(_, score)| score),
+        }))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(scores))
+}
+
+async fn save(guts: web::Data<AppGuts>, body: web::Json<Value>) -> AdminResult {
+    let path = string_field(&body, "path")?;
+    let len = guts.lock().unwrap().save_cassette(path)
+        .map_err(|e| AdminError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("failed to save {}: {}", path, e)))?;
+    info!("Saved {} entries to {}", len, path);
+    Ok(HttpResponse::Ok().json(json!({ "path": path, "recordings": len })))
+}
+
+async fn load(guts: web::Data<AppGuts>, body: web::Json<Value>) -> AdminResult {
+    let path = string_field(&body, "path")?;
+    let len = guts.lock().unwrap().load_cassette(path)
+        .map_err(|e| AdminError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("failed to load {}: {}", path, e)))?;
+    info!("Loaded {} entries from {}", len, path);
+    Ok(HttpResponse::Ok().json(json!({ "path": path, "recordings": len })))
+}
+
+async fn rewind(guts: web::Data<AppGuts>) -> AdminResult {
+    guts.lock().unwrap().rewind();
+    Ok(HttpResponse::Ok().json(state_json(&guts)))
+}
+
+async fn reset(guts: web::Data<AppGuts>) -> AdminResult {
+    guts.lock().unwrap().reset();
+    Ok(HttpResponse::Ok().json(state_json(&guts)))
+}
+
+async fn stop(stop_sender: web::Data<Sender<()>>) -> AdminResult {
+    info!("Stop requested through the admin API");
+    // Another stop may already be pending.
+    let _ = stop_sender.try_send(());
+    Ok(HttpResponse::Ok().json(json!({ "stopping": true })))
+}
+
+async fn not_found(req: HttpRequest) -> AdminResult {
+    Err(AdminError::new(StatusCode::NOT_FOUND, format!("no admin endpoint {} {}", req.method(), req.path())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{appguts::{ReplayConfig, UnsafeAppGuts}, ngrams::MiddlewareDataHttp, testutil::TempPath};
+    use actix_web::{body::MessageBody, dev::ServiceResponse, http::header::HeaderMap, test};
+    use bytes::Bytes;
+    use std::sync::{Arc, Mutex};
+
+    async fn call(guts: &AppGuts, req: test::TestRequest) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(guts.clone()))
+                .app_data(web::JsonConfig::default().error_handler(|e: JsonPayloadError, _| bad_request(e)))
+                .route("/__admin/state", web::put().to(set_state))
+                .route("/__admin/recordings", web::get().to(recordings))
+                .route("/__admin/recordings/{index}", web::get().to(recording))
+                .route("/__admin/load", web::post().to(load))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+        let resp: ServiceResponse = test::call_service(&app, req.to_request()).await;
+        let status = resp.status();
+        let body = resp.into_body().try_into_bytes().unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn guts() -> AppGuts {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        let http = MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new());
+        guts.insert_data("select 1".to_string(), None, Bytes::from_static(b"1\n"), Some(http.clone()), None);
+        guts.insert_data("select * from numbers(2)".to_string(), None, Bytes::from_static(b"0\n1\n"), Some(http), None);
+        Arc::new(Mutex::new(guts))
+    }
+
+    #[actix_web::test]
+    async fn state_and_recordings() {
+        let guts = guts();
+        let (status, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_json(json!({ "state": "replay" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "http": "replay", "native": "replay", "recordings": 2, "new_recordings": 2 }));
+
+        let set_native = json!({ "state": "passthrough", "protocol": "native" });
+        let (_, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_json(set_native)).await;
+        assert_eq!((&body["http"], &body["native"]), (&json!("replay"), &json!("passthrough")));
+        assert_eq!(guts.lock().unwrap().state(Protocol::Native), State::Passthrough);
+
+        let (_, body) = call(&guts, test::TestRequest::get().uri("/__admin/recordings")).await;
+        assert_eq!((&body["total"], &body["offset"]), (&json!(2), &json!(0)));
+        let summary = &body["recordings"][0];
+        assert_eq!(summary["query"], "select 1");
+        assert_eq!(summary["protocol"], "http");
+        assert_eq!(summary["status"], 200);
+        assert_eq!(summary["response_size"], 2);
+        assert!(summary["recorded_at"].is_string());
+
+        let (_, body) = call(&guts, test::TestRequest::get().uri("/__admin/recordings?contains=NUMBERS&offset=0&limit=1")).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["recordings"][0]["index"], 1);
+
+        let (status, body) = call(&guts, test::TestRequest::get().uri("/__admin/recordings/1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["response"], "0\n1\n");
+        assert_eq!(body["params"], json!(null));
+    }
+
+    #[actix_web::test]
+    async fn errors_are_json() {
+        let guts = guts();
+        let (status, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_json(json!({ "state": "sleep" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "unknown state: sleep");
+        let (status, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_json(json!({ "state": "replay", "protocol": "udp" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "unknown protocol: udp");
+        let (status, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_json(json!({ "state": "replay-or-record", "protocol": "native" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "replay-or-record is supported for http only");
+        assert_eq!(guts.lock().unwrap().state(Protocol::Native), State::Record);
+
+        let (status, body) = call(&guts, test::TestRequest::put().uri("/__admin/state").set_payload("{")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].is_string());
+
+        let missing = TempPath::new("missing");
+        let (status, _) = call(&guts, test::TestRequest::post().uri("/__admin/load").set_json(json!({ "path": missing.to_str() }))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(guts.lock().unwrap().entries().count(), 2);
+
+        let (status, body) = call(&guts, test::TestRequest::get().uri("/__admin/recordings?limit=many")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("invalid limit"), "{}", body);
+        let (status, body) = call(&guts, test::TestRequest::get().uri("/__admin/recordings/7")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "no recording 7");
+
+        let (status, body) = call(&guts, test::TestRequest::get().uri("/__admin/nothing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "no admin endpoint GET /__admin/nothing");
+    }
+}
@@ -1,66 +1,690 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use log::{debug, info};
 use std::{
+    fmt,
+    io,
+    path::Path,
+    str::FromStr,
     sync::{Arc, Mutex},
     vec::Vec,
 };
 
-use crate::ngrams::{Db, MiddlewareData, Dbly, MiddlewareDataHttp};
+use crate::{
+    cassette,
+    latency::{Latency, LatencyRule, Pacing},
+    matcher::{self, Matcher, NgramsMatcher, MATCHERS},
+    edit::ResponseEdit,
+    listing::{self, EntryFilter},
+    native::{self, ProtocolState, ServerHello, ServerPacket},
+    ngrams::{Db, MiddlewareData, Dbly, MiddlewareDataHttp, MiddlewareDataNative, MiddlewareDataRequest, LookupStats, ResponseTiming, SequencePolicy},
+};
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub enum State {
+    #[default]
     Record,
     Replay,
+    /// Proxy to ClickHouse without recording.
+    Passthrough,
+    /// Replay requests with a recording good enough, record the rest. HTTP only.
+    ReplayOrRecord,
 }
 
+impl FromStr for State {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(State::Record),
+            "replay" => Ok(State::Replay),
+            "passthrough" => Ok(State::Passthrough),
+            "replay-or-record" => Ok(State::ReplayOrRecord),
+            _ => Err(format!("unknown state: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Record => write!(f, "record"),
+            State::Replay => write!(f, "replay"),
+            State::Passthrough => write!(f, "passthrough"),
+            State::ReplayOrRecord => write!(f, "replay-or-record"),
+        }
+    }
+}
+
+/// Protocols whose state is set separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Http,
+    Native,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 2] = [Protocol::Http, Protocol::Native];
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Protocol::Http),
+            "native" => Ok(Protocol::Native),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Http => write!(f, "http"),
+            Protocol::Native => write!(f, "native"),
+        }
+    }
+}
+
+
+/// What to answer in Replay state when there is no recording good enough for the request.
+#[derive(Debug, Clone, Default)]
+pub enum MissPolicy {
+    /// ClickHouse-formatted exception with `X-ClickHouse-Exception-Code` header.
+    #[default]
+    ClickHouseError,
+    NotFound,
+    DefaultBody(Bytes),
+}
+
+impl FromStr for MissPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clickhouse-error" => Ok(MissPolicy::ClickHouseError),
+            "not-found" => Ok(MissPolicy::NotFound),
+            "default-body" => Ok(MissPolicy::DefaultBody(Bytes::new())),
+            _ => Err(format!("unknown miss policy: {}", s)),
+        }
+    }
+}
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// Minimal matcher score for a recording to be replayed.
+    pub min_score: f64,
+    pub miss_policy: MissPolicy,
+    /// Match by query fingerprints with numeric and string literals masked.
+    pub mask_literals: bool,
+    pub matcher: Arc<dyn Matcher>,
+    /// `n` of the n-gram based matchers, the ones `compare` tries too.
+    pub ngram_size: usize,
+    /// Replay recordings of the same request in recorded order, each once, instead of always the last one.
+    pub sequential: Option<SequencePolicy>,
+    /// Hello for native clients, the recorded one is used if not set.
+    pub server_hello: Option<ServerHello>,
+    /// Reproduce recorded response times for queries without a matching latency rule, answer right away if not set.
+    pub latency: Option<Latency>,
+    /// The first rule matching a query overrides `latency` for it.
+    pub latency_rules: Vec<LatencyRule>,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            min_score: 0.0,
+            miss_policy: MissPolicy::default(),
+            mask_literals: false,
+            matcher: Arc::new(NgramsMatcher::default()),
+            ngram_size: 3,
+            sequential: None,
+            server_hello: None,
+            latency: None,
+            latency_rules: Vec::new(),
+        }
+    }
+}
+
+/// Replay answer with its protocol parameters, HTTP status and headers or native protocol revision,
+/// and its simulated response times if latency is reproduced for it.
+pub enum Lookup<T> {
+    Hit(Bytes, T, Option<Pacing>, f64),
+    /// Best score among the candidates if there were any.
+    Miss(Option<f64>),
+}
+
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
 pub struct UnsafeAppGuts {
     db: Db,
-    state: State,
+    http_state: State,
+    native_state: State,
+    replay: ReplayConfig,
 }
 
 impl UnsafeAppGuts {
-    pub fn new() -> Self {
+    pub fn new(replay: ReplayConfig) -> Self {
         Self {
-            db: Vec::new(),
-            state: State::Record,
+            db: Db::new(),
+            http_state: State::Record,
+            native_state: State::Record,
+            replay,
         }
     }
 
-    pub fn insert_data(&mut self, req: String, resp: Bytes, http: Option<MiddlewareDataHttp>) {
-        self.db.push(MiddlewareData::new(req, resp, http));
+    pub fn insert_data(&mut self, req: String, meta: Option<MiddlewareDataRequest>, resp: Bytes, http: Option<MiddlewareDataHttp>, timing: Option<ResponseTiming>) {
+        self.db.push(MiddlewareData::new(req, meta, resp, http).with_timing(timing).recorded_now());
+
+        debug!("Added MiddlewareData to Db: {:?}", self.db.last());
+    }
+
+    pub fn insert_native(&mut self, req: String, resp: Bytes, native: MiddlewareDataNative, timing: Option<ResponseTiming>) {
+        self.db.push(MiddlewareData::new_native(req, resp, native).with_timing(timing).recorded_now());
+
+        debug!("Added native MiddlewareData to Db: {:?}", self.db.last());
+    }
+
+    /// Keeps the hello of the upstream to answer native clients with in Replay state.
+    pub fn record_server_hello(&mut self, hello: &ServerHello) {
+        // Encoded for a client of the highest revision, no field is left out then.
+        let mut buf = BytesMut::new();
+        hello.encode(&mut buf, native::MAX_REVISION);
+        self.db.set_server_hello(buf.freeze());
+
+        debug!("Recorded server hello: {:?}", hello);
+    }
+
+    /// Entries recorded since the start, the last load or reset that weren't deleted since.
+    /// Loaded and hand-written entries don't count.
+    pub fn new_recordings(&self) -> usize {
+        self.db.iter().filter(|data| data.is_new_recording()).count()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MiddlewareData> {
+        self.db.iter()
+    }
+
+    /// Drops all recordings and the recorded server hello.
+    pub fn reset(&mut self) {
+        self.db = Db::new();
+        info!("Db was reset");
+    }
+
+    pub fn load_cassette(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        self.db = cassette::load(path)?;
+        Ok(self.db.len())
+    }
+
+    pub fn save_cassette(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        cassette::save(path, &self.db)
+    }
+
+    pub fn entry(&self, idx: usize) -> Option<&MiddlewareData> {
+        self.db.get(idx)
+    }
+
+    /// Inserts the entry before `idx`, or appends it. Returns its index.
+    pub fn insert_entry(&mut self, idx: Option<usize>, data: MiddlewareData) -> usize {
+        let idx = idx.unwrap_or(self.db.len()).min(self.db.len());
+        self.db.insert(idx, data);
+        info!("Inserted entry {}", idx);
+        idx
+    }
+
+    pub fn delete_entry(&mut self, idx: usize) -> Option<MiddlewareData> {
+        let data = self.db.remove(idx)?;
+        info!("Deleted entry {}", idx);
+        Some(data)
+    }
+
+    /// Deletes all entries matching the filter, returns how many were deleted.
+    pub fn delete_entries(&mut self, filter: &EntryFilter) -> usize {
+        let deleted = self.db.retain(|data| !filter.matches(data));
+        info!("Deleted {} entries", deleted);
+        deleted
+    }
+
+    pub fn edit_entry(&mut self, idx: usize, edit: &ResponseEdit) -> Result<(), String> {
+        let data = self.db.get(idx).ok_or(format!("no entry {}", idx))?.clone();
+        self.db.replace(idx, edit.apply(data)?);
+        info!("Edited entry {}", idx);
+        Ok(())
+    }
 
-        debug!("Added MiddlewareData to Db: {:?}", self.db[self.db.len()-1]);
+    /// Moves the entry `from` to the index `to`, the entries between shift by one.
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Result<(), String> {
+        if to >= self.db.len() {
+            return Err(format!("no entry {}", to));
+        }
+        let data = self.db.remove(from).ok_or(format!("no entry {}", from))?;
+        self.db.insert(to, data);
+        info!("Moved entry {} to {}", from, to);
+        Ok(())
     }
 
+    /// Logs every recording, native answers are rendered with their blocks as tables.
     pub fn show_data(&self) {
-        info!("{:?}", &self.db)
+        for data in self.db.iter() {
+            match data.native() {
+                Some(_) => info!("{:?}\n{}", data.request(), listing::response_text(data)),
+                None => info!("{:?}", data),
+            }
+        }
     }
 
-    pub fn find_best_answer(&self, req: String) -> (Bytes, MiddlewareDataHttp) {
-        self.db.find_best_response(req)
+    pub fn lookup_stats(&self) -> &LookupStats {
+        self.db.stats()
     }
 
-    pub fn is_record_state(&self) -> bool {
-        match self.state {
-            State::Record => true,
-            State::Replay => false,
+    pub fn find_best_answer(&mut self, req: String, meta: Option<MiddlewareDataRequest>) -> Lookup<MiddlewareDataHttp> {
+        let latency = self.latency_for(&req);
+        let req = MiddlewareData::new(req, meta, Bytes::new(), None);
+        match self.find_best_entry(&req) {
+            Ok((data, score)) => {
+                let pacing = data.timing().zip(latency).map(|(timing, latency)| latency.pacing(timing));
+                Lookup::Hit(data.response().clone(), data.http().expect("have no http stuff").clone(), pacing, score)
+            }
+            Err(score) => Lookup::Miss(score),
         }
     }
 
-    pub fn change_state(&mut self) {
-        self.state = match self.state {
-            State::Record => State::Replay,
-            State::Replay => State::Record,
+    /// Same as `find_best_answer` for a query received over the native protocol.
+    pub fn find_best_native_answer(&mut self, req: String, native: MiddlewareDataNative) -> Lookup<MiddlewareDataNative> {
+        let latency = self.latency_for(&req);
+        let req = MiddlewareData::new_native(req, Bytes::new(), native);
+        match self.find_best_entry(&req) {
+            Ok((data, score)) => {
+                let pacing = data.timing().zip(latency).map(|(timing, latency)| latency.pacing(timing));
+                Lookup::Hit(data.response().clone(), *data.native().expect("have no native stuff"), pacing, score)
+            }
+            Err(score) => Lookup::Miss(score),
+        }
+    }
+
+    /// Latency of the first rule matching the query, or the global one.
+    fn latency_for(&self, query: &str) -> Option<Latency> {
+        self.replay.latency_rules.iter()
+            .find(|rule| rule.matches(query))
+            .map(|rule| rule.latency)
+            .or(self.replay.latency)
+    }
+
+    /// Recording to replay for the request and its score, applying the score threshold and sequential replay.
+    /// Returns the best score if there is nothing to replay.
+    fn find_best_entry(&mut self, req: &MiddlewareData) -> Result<(&MiddlewareData, f64), Option<f64>> {
+        let (idx, score) = match self.db.find_best_response(req, self.replay.matcher.as_ref(), self.replay.mask_literals) {
+            Some((idx, score)) if score >= self.replay.min_score => (idx, score),
+            Some((_, score)) => return Err(Some(score)),
+            None => return Err(None),
+        };
+
+        let idx = match self.replay.sequential {
+            Some(policy) => match self.db.consume(idx, policy) {
+                Some(idx) => idx,
+                None => {
+                    info!("All recordings for {:?} were already replayed", req.request());
+                    return Err(Some(score));
+                }
+            },
+            None => idx,
         };
-        info!("App state changed! Now: {:?}", &self.state);
+
+        Ok((self.db.get(idx).unwrap(), score))
+    }
+
+    /// Best recording index and score for every matcher, ignoring the replay threshold.
+    /// The n-gram based matchers use the configured `n`, the configured matcher is used as is.
+    pub fn compare_matchers(&self, req: String) -> Vec<(String, Option<(usize, f64)>)> {
+        let req = MiddlewareData::new(req, None, Bytes::new(), None);
+        MATCHERS.iter()
+            .map(|name| {
+                let matcher = matcher::from_name(name, self.replay.ngram_size).unwrap();
+                if matcher.name() == self.replay.matcher.name() { self.replay.matcher.clone() } else { matcher }
+            })
+            .map(|matcher| (matcher.name(), self.db.best_match(&req, matcher.as_ref(), self.replay.mask_literals)))
+            .collect()
+    }
+
+    pub fn matcher(&self) -> &dyn Matcher {
+        self.replay.matcher.as_ref()
+    }
+
+    /// Hello for native clients: the configured one, or else the last recorded one.
+    pub fn server_hello(&self) -> Option<ServerHello> {
+        self.replay.server_hello.clone().or_else(|| {
+            let state = ProtocolState { revision: native::MAX_REVISION, compression: None };
+            match native::decode_server(self.db.server_hello()?, &state).ok()? {
+                (ServerPacket::Hello(hello), _) => Some(hello),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn miss_policy(&self) -> &MissPolicy {
+        &self.replay.miss_policy
+    }
+
+    pub fn state(&self, protocol: Protocol) -> State {
+        match protocol {
+            Protocol::Http => self.http_state,
+            Protocol::Native => self.native_state,
+        }
+    }
+
+    /// Sets the state of one protocol, or of both if none is given. Sequences start over if anything changed.
+    /// Native connections can't be replayed and recorded at once, without a protocol ReplayOrRecord is set for HTTP only.
+    pub fn set_state(&mut self, protocol: Option<Protocol>, state: State) -> Result<(), String> {
+        let protocol = match (protocol, state) {
+            (Some(Protocol::Native), State::ReplayOrRecord) => return Err(format!("{} is supported for http only", state)),
+            (None, State::ReplayOrRecord) => Some(Protocol::Http),
+            (protocol, _) => protocol,
+        };
+        let mut changed = false;
+        for protocol in Protocol::ALL.into_iter().filter(|p| protocol.is_none_or(|protocol| protocol == *p)) {
+            let current = match protocol {
+                Protocol::Http => &mut self.http_state,
+                Protocol::Native => &mut self.native_state,
+            };
+            if *current != state {
+                *current = state;
+                changed = true;
+                info!("App state changed! Now {}: {}", protocol, state);
+            }
+        }
+        if changed {
+            self.db.rewind();
+        }
+        Ok(())
+    }
+
+    pub fn rewind(&mut self) {
+        self.db.rewind();
+    }
+
+    /// Switches Replay to Record and anything else to Replay, for every protocol.
+    pub fn change_state(&mut self) {
+        for protocol in Protocol::ALL {
+            let state = match self.state(protocol) {
+                State::Replay => State::Record,
+                State::Record | State::Passthrough | State::ReplayOrRecord => State::Replay,
+            };
+            self.set_state(Some(protocol), state).unwrap();
+        }
     }
 
 }
 
 pub type AppGuts = Arc<Mutex<UnsafeAppGuts>>;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use std::time::Duration;
+
+    fn record(guts: &mut UnsafeAppGuts, query: &str) {
+        let http = MiddlewareDataHttp::new(actix_web::http::StatusCode::OK, actix_web::http::header::HeaderMap::new());
+        guts.insert_data(query.to_string(), None, Bytes::from(format!("{}\n", query)), Some(http), None);
+    }
+
+    fn record_answer(guts: &mut UnsafeAppGuts, query: &str, answer: &'static str) {
+        let http = MiddlewareDataHttp::new(actix_web::http::StatusCode::OK, actix_web::http::header::HeaderMap::new());
+        guts.insert_data(query.to_string(), None, Bytes::from_static(answer.as_bytes()), Some(http), None);
+    }
+
+    fn answer(guts: &mut UnsafeAppGuts, query: &str) -> Option<Bytes> {
+        match guts.find_best_answer(query.to_string(), None) {
+            Lookup::Hit(body, _, _, _) => Some(body),
+            Lookup::Miss(_) => None,
+        }
+    }
+
+    #[test]
+    fn load_replaces_the_db() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        record(&mut guts, "select 1");
+        let cassette = TempPath::new("appguts");
+        assert_eq!(guts.save_cassette(&cassette).unwrap(), 1);
+
+        record(&mut guts, "select 2");
+        assert_eq!(guts.load_cassette(&cassette).unwrap(), 1);
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"select 1\n");
+        assert!(answer(&mut guts, "select 2").is_none());
+        assert!(guts.load_cassette(TempPath::new("missing")).is_err());
+        assert_eq!(guts.db.len(), 1);
+    }
+
+    #[test]
+    fn answers_below_min_score_are_misses() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig { min_score: 0.5, ..ReplayConfig::default() });
+        record(&mut guts, "select count() from system.tables");
+        assert!(answer(&mut guts, "select count() from system.tables").is_some());
+        assert!(answer(&mut guts, "select count() from system.columns").is_some());
+        match guts.find_best_answer("select count() from events where x = 1".to_string(), None) {
+            Lookup::Miss(Some(score)) => assert!(score < 0.5, "{}", score),
+            _ => panic!("expected a miss with a score"),
+        }
+        // Nothing shares an n-gram with the request.
+        assert!(matches!(guts.find_best_answer("insert into events values (1)".to_string(), None), Lookup::Miss(None)));
+    }
+
+    #[test]
+    fn miss_policies() {
+        assert!(matches!("clickhouse-error".parse(), Ok(MissPolicy::ClickHouseError)));
+        assert!(matches!("not-found".parse(), Ok(MissPolicy::NotFound)));
+        assert!(matches!("default-body".parse(), Ok(MissPolicy::DefaultBody(_))));
+        assert!("teapot".parse::<MissPolicy>().is_err());
+    }
+
+    fn sequence(policy: SequencePolicy, lookups: usize) -> Vec<Option<Bytes>> {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig { sequential: Some(policy), ..ReplayConfig::default() });
+        record_answer(&mut guts, "select now()", "first");
+        record_answer(&mut guts, "select version()", "version");
+        record_answer(&mut guts, "select now()", "second");
+        (0..lookups).map(|_| answer(&mut guts, "select now()")).collect()
+    }
+
+    #[test]
+    fn sequential_replay() {
+        let [first, second] = [Some(Bytes::from_static(b"first")), Some(Bytes::from_static(b"second"))];
+        assert_eq!(sequence(SequencePolicy::RepeatLast, 3), [first.clone(), second.clone(), second.clone()]);
+        assert_eq!(sequence(SequencePolicy::Error, 3), [first.clone(), second.clone(), None]);
+        assert_eq!(sequence(SequencePolicy::Cycle, 3), [first.clone(), second.clone(), first.clone()]);
 
+        let mut guts = UnsafeAppGuts::new(ReplayConfig { sequential: Some(SequencePolicy::Error), ..ReplayConfig::default() });
+        record_answer(&mut guts, "select now()", "first");
+        assert_eq!(answer(&mut guts, "select now()"), first);
+        assert_eq!(answer(&mut guts, "select now()"), None);
+        guts.rewind();
+        assert_eq!(answer(&mut guts, "select now()"), first);
+        guts.change_state();
+        assert_eq!(answer(&mut guts, "select now()"), first);
+    }
+
+    #[test]
+    fn without_sequential_replay_the_last_recording_wins() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        record_answer(&mut guts, "select now()", "first");
+        record_answer(&mut guts, "select now()", "second");
+        for _ in 0..2 {
+            assert_eq!(answer(&mut guts, "select now()").unwrap().as_ref(), b"second");
+        }
+    }
+
+    #[test]
+    fn native_answers() {
+        let native = MiddlewareDataNative { revision: 54453, compression: true };
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        record(&mut guts, "select 1");
+        guts.insert_native("select 1".to_string(), Bytes::from_static(b"packets"), native, None);
+
+        match guts.find_best_native_answer("select 1".to_string(), MiddlewareDataNative { revision: 54460, compression: false }) {
+            Lookup::Hit(packets, recorded, _, score) => {
+                assert_eq!(packets.as_ref(), b"packets");
+                assert_eq!((recorded.revision, recorded.compression, score), (54453, true, 1.0));
+            }
+            Lookup::Miss(_) => panic!("expected the native recording"),
+        }
+        assert!(matches!(guts.find_best_native_answer("insert into t values".to_string(), native), Lookup::Miss(None)));
+    }
+
+    #[test]
+    fn first_matching_latency_rule_wins() {
+        let slow = Latency { scale: 2.0, ..Latency::default() };
+        let replay = ReplayConfig {
+            latency: Some(Latency::default()),
+            latency_rules: vec![LatencyRule::new("FROM events", slow), LatencyRule::new("select", Latency::speed(4.0))],
+            ..ReplayConfig::default()
+        };
+        let mut guts = UnsafeAppGuts::new(replay);
+        let timing = ResponseTiming { first_byte: Duration::from_millis(10), total: Duration::from_millis(40) };
+        let http = MiddlewareDataHttp::new(actix_web::http::StatusCode::OK, actix_web::http::header::HeaderMap::new());
+        guts.insert_data("select * from events".to_string(), None, Bytes::new(), Some(http), Some(timing));
+
+        for (query, total) in [("select * from events", 80), ("SELECT * FROM system.events", 10), ("SELECT * FROM events FINAL", 80)] {
+            match guts.find_best_answer(query.to_string(), None) {
+                Lookup::Hit(_, _, Some(pacing), _) => assert_eq!(pacing.simulated.total, Duration::from_millis(total), "{}", query),
+                _ => panic!("expected a paced hit for {}", query),
+            }
+        }
+        assert_eq!(guts.latency_for("show tables"), Some(Latency::default()));
+        guts.replay.latency = None;
+        assert_eq!(guts.latency_for("show tables"), None);
+
+        // Recordings without timings are answered right away.
+        record(&mut guts, "show tables");
+        assert!(matches!(guts.find_best_answer("show tables".to_string(), None), Lookup::Hit(_, _, None, _)));
+    }
+
+    #[test]
+    fn states_per_protocol() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        guts.set_state(Some(Protocol::Native), State::Passthrough).unwrap();
+        assert_eq!((guts.state(Protocol::Http), guts.state(Protocol::Native)), (State::Record, State::Passthrough));
+        guts.change_state();
+        assert_eq!((guts.state(Protocol::Http), guts.state(Protocol::Native)), (State::Replay, State::Replay));
+        guts.change_state();
+        assert_eq!((guts.state(Protocol::Http), guts.state(Protocol::Native)), (State::Record, State::Record));
+        guts.set_state(None, State::Passthrough).unwrap();
+        assert!(Protocol::ALL.iter().all(|protocol| guts.state(*protocol) == State::Passthrough));
+
+        for state in [State::Record, State::Replay, State::Passthrough] {
+            assert_eq!(state.to_string().parse::<State>().unwrap(), state);
+        }
+        assert!("native".parse::<State>().is_err());
+        assert_eq!("native".parse::<Protocol>().unwrap(), Protocol::Native);
+    }
+
+    #[test]
+    fn changing_the_state_starts_sequences_over() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig { sequential: Some(SequencePolicy::Error), ..ReplayConfig::default() });
+        record_answer(&mut guts, "select 1", "first");
+        record_answer(&mut guts, "select 1", "second");
+        guts.set_state(None, State::Replay).unwrap();
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"first");
+
+        // Setting the current state again keeps the position.
+        guts.set_state(Some(Protocol::Http), State::Replay).unwrap();
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"second");
+        guts.set_state(Some(Protocol::Native), State::Passthrough).unwrap();
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"first");
+    }
+
+    #[test]
+    fn edited_db_is_reindexed() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        for query in ["select 1", "select 2", "select 3"] {
+            record(&mut guts, query);
+        }
+        let (_, extra) = crate::edit::handwritten_entry([("query", "select 4"), ("body", "4\n")]).unwrap();
+        assert_eq!(guts.insert_entry(Some(0), extra), 0);
+        assert_eq!(guts.delete_entry(2).unwrap().request(), "select 2");
+        assert_eq!(guts.delete_entry(2).unwrap().request(), "select 3");
+        assert!(guts.delete_entry(2).is_none());
+        assert_eq!(guts.entries().map(MiddlewareData::request).collect::<Vec<_>>(), ["select 4", "select 1"]);
+        assert_eq!(answer(&mut guts, "select 4").unwrap().as_ref(), b"4\n");
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"select 1\n");
+
+        guts.move_entry(1, 0).unwrap();
+        assert!(guts.move_entry(0, 2).is_err());
+        assert_eq!(guts.entry(0).unwrap().request(), "select 1");
+
+        let mut edit = ResponseEdit::default();
+        edit.set("body", "one\n").unwrap();
+        guts.edit_entry(0, &edit).unwrap();
+        assert!(guts.edit_entry(5, &edit).is_err());
+        assert_eq!(answer(&mut guts, "select 1").unwrap().as_ref(), b"one\n");
+
+        let filter = crate::listing::Listing::from_options([("contains", "select 4")]).unwrap().filter;
+        assert_eq!(guts.delete_entries(&filter), 1);
+        assert_eq!(guts.entries().map(MiddlewareData::request).collect::<Vec<_>>(), ["select 1"]);
+    }
+
+    #[test]
+    fn new_recordings() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        record(&mut guts, "select 1");
+        record(&mut guts, "select 2");
+        let (_, handwritten) = crate::edit::handwritten_entry([("query", "select 3")]).unwrap();
+        guts.insert_entry(None, handwritten);
+        assert_eq!(guts.new_recordings(), 2);
+
+        guts.delete_entry(0).unwrap();
+        assert_eq!(guts.new_recordings(), 1);
+
+        let cassette = TempPath::new("new-recordings");
+        guts.save_cassette(&cassette).unwrap();
+        record(&mut guts, "select 4");
+        guts.load_cassette(&cassette).unwrap();
+        assert_eq!((guts.entries().count(), guts.new_recordings()), (2, 0));
+
+        record(&mut guts, "select 5");
+        guts.reset();
+        assert_eq!(guts.new_recordings(), 0);
+    }
+
+    #[test]
+    fn replay_or_record() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        guts.set_state(Some(Protocol::Native), State::Replay).unwrap();
+        assert!(guts.set_state(Some(Protocol::Native), State::ReplayOrRecord).is_err());
+        // Without a protocol it is set for HTTP only.
+        guts.set_state(None, State::ReplayOrRecord).unwrap();
+        assert_eq!((guts.state(Protocol::Http), guts.state(Protocol::Native)), (State::ReplayOrRecord, State::Replay));
+        assert_eq!("replay-or-record".parse::<State>().unwrap(), State::ReplayOrRecord);
+
+        record(&mut guts, "select 1");
+        let cassette = TempPath::new("replay-or-record");
+        guts.save_cassette(&cassette).unwrap();
+        guts.load_cassette(&cassette).unwrap();
+        record(&mut guts, "select 2");
+        assert_eq!((guts.entries().count(), guts.new_recordings()), (2, 1));
+
+        guts.change_state();
+        assert_eq!((guts.state(Protocol::Http), guts.state(Protocol::Native)), (State::Replay, State::Record));
+    }
+
+    #[test]
+    fn server_hello() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        assert!(guts.server_hello().is_none());
+        guts.record_server_hello(&ServerHello::with_version("24.3.2").unwrap());
+        let cassette = TempPath::new("server-hello");
+        guts.save_cassette(&cassette).unwrap();
+        guts.reset();
+        assert!(guts.server_hello().is_none());
+        guts.load_cassette(&cassette).unwrap();
+        let hello = guts.server_hello().unwrap();
+        assert_eq!((hello.version_major, hello.version_minor, hello.version_patch, hello.timezone.as_str()), (24, 3, 2, "UTC"));
+
+        // A configured hello wins over the recorded one.
+        let replay = ReplayConfig { server_hello: Some(ServerHello::with_version("23.8.1").unwrap()), ..ReplayConfig::default() };
+        let mut guts = UnsafeAppGuts::new(replay);
+        guts.load_cassette(&cassette).unwrap();
+        assert_eq!(guts.server_hello().unwrap().version_major, 23);
+    }
+}
@@ -0,0 +1,437 @@
+use actix_web::http::{
+    Method,
+    StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{fs, io::{self, Write}, path::Path, time::{Duration, UNIX_EPOCH}};
+
+use crate::ngrams::{Db, MiddlewareData, MiddlewareDataHttp, MiddlewareDataNative, MiddlewareDataRequest, ResponseChunk, ResponseTiming};
+
+// Cassette layout: MAGIC, u32 version, u32 entries count, then entries, then the optional recorded native server hello packet.
+// Every string and byte blob is stored as u32 length + raw bytes, all integers are big-endian.
+// An entry is the request, its optional meta (method, path, query, headers and body), the answer, optional HTTP status,
+// headers and (microseconds, length) of every body chunk, optional native protocol parameters, optional microseconds
+// to the first byte and to the end, and when it was recorded in microseconds since the Unix epoch if that is known.
+// Optional parts are preceded by a u8 flag.
+const MAGIC: &[u8; 4] = b"NRSC";
+pub const VERSION: u32 = 1;
+
+pub fn save(path: impl AsRef<Path>, db: &Db) -> io::Result<usize> {
+    let mut buf = BytesMut::new();
+    buf.put_slice(MAGIC);
+    buf.put_u32(VERSION);
+    write_len(&mut buf, db.len())?;
+    for data in db.iter() {
+        write_entry(&mut buf, data)?;
+    }
+    match db.server_hello() {
+        Some(hello) => {
+            buf.put_u8(1);
+            write_blob(&mut buf, hello)?;
+        }
+        None => buf.put_u8(0),
+    }
+
+    write_atomically(path.as_ref(), &buf)?;
+    Ok(db.len())
+}
+
+/// Writes a temp file next to `path` and renames it over, a crash midway leaves the old file intact.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().ok_or_else(|| invalid_input("cassette path has no file name"))?.to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Db> {
+    let mut buf = Bytes::from(fs::read(path)?);
+
+    if buf.len() < MAGIC.len() || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not a cassette file"));
+    }
+    buf.advance(MAGIC.len());
+
+    let version = read_u32(&mut buf)?;
+    if version != VERSION {
+        return Err(invalid_data(format!("unsupported cassette version {}", version)));
+    }
+
+    let len = read_u32(&mut buf)?;
+    let mut db = Db::new();
+    for _ in 0..len {
+        db.push(read_entry(&mut buf)?);
+    }
+    match read_u8(&mut buf)? {
+        0 => {}
+        1 => db.set_server_hello(read_blob(&mut buf)?),
+        flag => return Err(invalid_data(format!("invalid server hello flag {}", flag))),
+    }
+
+    Ok(db)
+}
+
+fn write_entry(buf: &mut BytesMut, data: &MiddlewareData) -> io::Result<()> {
+    write_blob(buf, data.request().as_bytes())?;
+
+    match data.request_meta() {
+        Some(meta) => {
+            buf.put_u8(1);
+            write_blob(buf, meta.method().as_str().as_bytes())?;
+            write_blob(buf, meta.path().as_bytes())?;
+            write_len(buf, meta.query().len())?;
+            for (key, value) in meta.query() {
+                write_blob(buf, key.as_bytes())?;
+                write_blob(buf, value.as_bytes())?;
+            }
+            write_headers(buf, meta.headers())?;
+            write_blob(buf, meta.body())?;
+        }
+        None => buf.put_u8(0),
+    }
+
+    write_blob(buf, data.response())?;
+
+    match data.http() {
+        Some(http) => {
+            let (status, headers) = http.split();
+            buf.put_u8(1);
+            buf.put_u16(status.as_u16());
+            write_headers(buf, &headers)?;
+            write_len(buf, http.chunks().len())?;
+            for chunk in http.chunks() {
+                buf.put_u64(chunk.at.as_micros() as u64);
+                write_len(buf, chunk.len)?;
+            }
+        }
+        None => buf.put_u8(0),
+    }
+
+    match data.native() {
+        Some(native) => {
+            buf.put_u8(1);
+            buf.put_u64(native.revision);
+            buf.put_u8(native.compression.into());
+        }
+        None => buf.put_u8(0),
+    }
+
+    match data.timing() {
+        Some(timing) => {
+            buf.put_u8(1);
+            buf.put_u64(timing.first_byte.as_micros() as u64);
+            buf.put_u64(timing.total.as_micros() as u64);
+        }
+        None => buf.put_u8(0),
+    }
+
+    match data.recorded_at().and_then(|at| at.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => {
+            buf.put_u8(1);
+            buf.put_u64(since_epoch.as_micros() as u64);
+        }
+        None => buf.put_u8(0),
+    }
+    Ok(())
+}
+
+fn read_entry(buf: &mut Bytes) -> io::Result<MiddlewareData> {
+    let req = read_string(buf)?;
+
+    let meta = match read_u8(buf)? {
+        0 => None,
+        1 => {
+            let method = Method::from_bytes(&read_blob(buf)?).map_err(invalid_data)?;
+            let path = read_string(buf)?;
+            let query_len = read_u32(buf)?;
+            let mut query = Vec::new();
+            for _ in 0..query_len {
+                query.push((read_string(buf)?, read_string(buf)?));
+            }
+            let headers = read_headers(buf)?;
+            let body = read_blob(buf)?;
+            Some(MiddlewareDataRequest::new(method, path, query, &headers, body))
+        }
+        flag => return Err(invalid_data(format!("invalid request meta flag {}", flag))),
+    };
+
+    let resp = read_blob(buf)?;
+
+    let http = match read_u8(buf)? {
+        0 => None,
+        1 => {
+            let status = StatusCode::from_u16(read_u16(buf)?).map_err(invalid_data)?;
+            let headers = read_headers(buf)?;
+            let len = read_u32(buf)?;
+            let mut chunks = Vec::new();
+            for _ in 0..len {
+                chunks.push(ResponseChunk {
+                    at: Duration::from_micros(read_u64(buf)?),
+                    len: read_u32(buf)? as usize,
+                });
+            }
+            Some(MiddlewareDataHttp::new(status, headers).with_chunks(chunks))
+        }
+        flag => return Err(invalid_data(format!("invalid http flag {}", flag))),
+    };
+
+    let native = match read_u8(buf)? {
+        0 => None,
+        1 => Some(MiddlewareDataNative {
+            revision: read_u64(buf)?,
+            compression: read_u8(buf)? != 0,
+        }),
+        flag => return Err(invalid_data(format!("invalid native flag {}", flag))),
+    };
+
+    let timing = match read_u8(buf)? {
+        0 => None,
+        1 => Some(ResponseTiming {
+            first_byte: Duration::from_micros(read_u64(buf)?),
+            total: Duration::from_micros(read_u64(buf)?),
+        }),
+        flag => return Err(invalid_data(format!("invalid timing flag {}", flag))),
+    };
+
+    let recorded_at = match read_u8(buf)? {
+        0 => None,
+        1 => Some(UNIX_EPOCH + Duration::from_micros(read_u64(buf)?)),
+        flag => return Err(invalid_data(format!("invalid recorded at flag {}", flag))),
+    };
+
+    let data = match native {
+        Some(native) => MiddlewareData::new_native(req, resp, native),
+        None => MiddlewareData::new(req, meta, resp, http),
+    };
+    Ok(data.with_timing(timing).with_recorded_at(recorded_at))
+}
+
+fn write_headers(buf: &mut BytesMut, headers: &HeaderMap) -> io::Result<()> {
+    write_len(buf, headers.len())?;
+    for (name, value) in headers.iter() {
+        write_blob(buf, name.as_str().as_bytes())?;
+        write_blob(buf, value.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_headers(buf: &mut Bytes) -> io::Result<HeaderMap> {
+    let len = read_u32(buf)?;
+    let mut headers = HeaderMap::new();
+    for _ in 0..len {
+        let name = HeaderName::from_bytes(&read_blob(buf)?).map_err(invalid_data)?;
+        let value = HeaderValue::from_maybe_shared(read_blob(buf)?).map_err(invalid_data)?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn write_blob(buf: &mut BytesMut, blob: &[u8]) -> io::Result<()> {
+    write_len(buf, blob.len())?;
+    buf.put_slice(blob);
+    Ok(())
+}
+
+/// Lengths and counts are stored as u32, a cassette can't hold anything bigger.
+fn write_len(buf: &mut BytesMut, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid_input(&format!("{} is too big a length for a cassette", len)))?;
+    buf.put_u32(len);
+    Ok(())
+}
+
+fn read_blob(buf: &mut Bytes) -> io::Result<Bytes> {
+    let len = read_u32(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(unexpected_eof());
+    }
+    Ok(buf.split_to(len))
+}
+
+fn read_string(buf: &mut Bytes) -> io::Result<String> {
+    String::from_utf8(read_blob(buf)?.to_vec()).map_err(invalid_data)
+}
+
+fn read_u8(buf: &mut Bytes) -> io::Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(unexpected_eof());
+    }
+    Ok(buf.get_u8())
+}
+
+fn read_u16(buf: &mut Bytes) -> io::Result<u16> {
+    if buf.remaining() < 2 {
+        return Err(unexpected_eof());
+    }
+    Ok(buf.get_u16())
+}
+
+fn read_u32(buf: &mut Bytes) -> io::Result<u32> {
+    if buf.remaining() < 4 {
+        return Err(unexpected_eof());
+    }
+    Ok(buf.get_u32())
+}
+
+fn read_u64(buf: &mut Bytes) -> io::Result<u64> {
+    if buf.remaining() < 8 {
+        return Err(unexpected_eof());
+    }
+    Ok(buf.get_u64())
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated cassette")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use std::time::SystemTime;
+
+    fn tsv_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("x-clickhouse-format"), HeaderValue::from_static("TSV"));
+        headers
+    }
+
+    fn header_pairs(headers: &HeaderMap) -> Vec<(String, Vec<u8>)> {
+        let mut pairs = headers.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect::<Vec<_>>();
+        pairs.sort();
+        pairs
+    }
+
+    fn file_header(version: u32, entries: u32) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_slice(MAGIC);
+        buf.put_u32(version);
+        buf.put_u32(entries);
+        buf
+    }
+
+    fn load_bytes(bytes: &[u8]) -> io::Result<Db> {
+        let cassette = TempPath::new("cassette");
+        fs::write(&cassette, bytes)?;
+        load(&cassette)
+    }
+
+    fn timing() -> ResponseTiming {
+        ResponseTiming { first_byte: Duration::from_micros(1200), total: Duration::from_millis(25) }
+    }
+
+    fn recorded_at() -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(1_714_564_800_123_456)
+    }
+
+    fn assert_http_entry(data: &MiddlewareData) {
+        assert_eq!(data.request(), "select 1");
+        assert_eq!(data.response().as_ref(), b"1\n");
+        let (status, headers) = data.http().unwrap().split();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header_pairs(&headers), header_pairs(&tsv_headers()));
+    }
+
+    fn assert_native_entry(data: &MiddlewareData) {
+        assert_eq!(data.request(), "select 2");
+        assert_eq!(data.response().as_ref(), b"packets");
+        let native = data.native().unwrap();
+        assert_eq!((native.revision, native.compression), (54453, true));
+        assert!(data.http().is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let meta = MiddlewareDataRequest::new(
+            Method::POST,
+            "/".to_string(),
+            vec![("database".to_string(), "default".to_string())],
+            &tsv_headers(),
+            Bytes::from_static(b"select 1"),
+        );
+        let chunks = vec![
+            ResponseChunk { at: Duration::from_micros(1500), len: 1 },
+            ResponseChunk { at: Duration::from_millis(20), len: 1 },
+        ];
+        let http = MiddlewareDataHttp::new(StatusCode::OK, tsv_headers()).with_chunks(chunks);
+        let mut db = Db::new();
+        db.push(MiddlewareData::new("select 1".to_string(), Some(meta), Bytes::from_static(b"1\n"), Some(http)).with_timing(Some(timing())).with_recorded_at(Some(recorded_at())));
+        db.push(MiddlewareData::new_native("select 2".to_string(), Bytes::from_static(b"packets"), MiddlewareDataNative { revision: 54453, compression: true }));
+        db.set_server_hello(Bytes::from_static(b"hello"));
+
+        let cassette = TempPath::new("round-trip");
+        assert_eq!(save(&cassette, &db).unwrap(), 2);
+        let loaded = load(&cassette).unwrap();
+        let entries = loaded.iter().collect::<Vec<_>>();
+
+        assert_eq!(entries.len(), 2);
+        assert_http_entry(entries[0]);
+        let meta = entries[0].request_meta().unwrap();
+        assert_eq!(meta.method(), Method::POST);
+        assert_eq!(meta.path(), "/");
+        assert_eq!(meta.query(), [("database".to_string(), "default".to_string())]);
+        assert_eq!(header_pairs(meta.headers()), header_pairs(&tsv_headers()));
+        assert_eq!(meta.body().as_ref(), b"select 1");
+        assert!(entries[0].native().is_none());
+        let http = entries[0].http().unwrap();
+        assert_eq!(entries[0].timing(), Some(timing()));
+        assert_eq!(entries[1].timing(), None);
+        assert_eq!(entries[0].recorded_at(), Some(recorded_at()));
+        assert_eq!(entries[1].recorded_at(), None);
+        assert_eq!(http.chunks().iter().map(|chunk| (chunk.at, chunk.len)).collect::<Vec<_>>(), [(Duration::from_micros(1500), 1), (Duration::from_millis(20), 1)]);
+        assert_native_entry(entries[1]);
+
+        assert_eq!(loaded.server_hello().unwrap().as_ref(), b"hello");
+    }
+
+    #[test]
+    fn save_replaces_the_file() {
+        let dir = TempPath::dir("replace");
+        let cassette = dir.join("recordings.nrsc");
+        fs::write(&cassette, b"old contents").unwrap();
+        save(&cassette, &Db::new()).unwrap();
+        assert_eq!(load(&cassette).unwrap().len(), 0);
+        // The temp file was renamed over the cassette.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(save(&*dir, &Db::new()).unwrap_err().kind(), io::ErrorKind::IsADirectory);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert_eq!(load_bytes(b"garbage").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        for version in [0, VERSION + 1] {
+            assert_eq!(load_bytes(&file_header(version, 0)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(load_bytes(&file_header(VERSION, 1)).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_lengths_are_refused() {
+        let mut buf = BytesMut::new();
+        write_len(&mut buf, u32::MAX as usize).unwrap();
+        let e = write_len(&mut buf, u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(buf.len(), 4);
+    }
+}
@@ -1,5 +1,7 @@
 use clap::{Command, Arg, ArgMatches};
 
+use crate::{latency::Latency, matcher::MATCHERS};
+
 pub fn get_cli_args() -> ArgMatches {
     Command::new("proxy")
         .version("0.1")
@@ -29,7 +31,7 @@ pub fn get_cli_args() -> ArgMatches {
                 .long("https_port_local")
                 .value_name("PORT")
                 .default_value("8443")
-                .help("Port for encrypted HTTPS queries on local host")
+                .help("Port for encrypted HTTPS queries on local host, only served with this or another secure port or a --tls_* option given")
                 .takes_value(true)
                 .required(false),
         )
@@ -47,7 +49,7 @@ pub fn get_cli_args() -> ArgMatches {
                 .long("tcp_port_secure_local")
                 .value_name("PORT")
                 .default_value("9440")
-                .help("Port for TLS-encrypted native TCP/IP queries on local host")
+                .help("Port for TLS-encrypted native TCP/IP queries on local host, only served with this or another secure port or a --tls_* option given")
                 .takes_value(true)
                 .required(false),
         )
@@ -79,7 +81,7 @@ pub fn get_cli_args() -> ArgMatches {
                 .required(false),
         )
         .arg(
-            Arg::new("tcp_port_secur_clickhousee")
+            Arg::new("tcp_port_secure_clickhouse")
                 .long("tcp_port_secure_clickhouse")
                 .value_name("PORT")
                 .default_value("9440")
@@ -87,6 +89,63 @@ pub fn get_cli_args() -> ArgMatches {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::new("tls_cert")
+                .long("tls_cert")
+                .value_name("PATH")
+                .requires("tls_key")
+                .help("PEM certificate chain for the HTTPS and secure native ports on local host, a certificate signed by a generated local CA is used without it")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("tls_key")
+                .long("tls_key")
+                .value_name("PATH")
+                .requires("tls_cert")
+                .help("PEM private key of the --tls_cert certificate")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("tls_san")
+                .long("tls_san")
+                .value_name("NAME")
+                .help("Hostname or IP address to add to the generated certificate besides localhost and 127.0.0.1, may be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("tls_ca")
+                .long("tls_ca")
+                .value_name("PATH")
+                .help("Where to write the PEM of the generated local CA for clients to trust, network-replay-server/ca.pem in $XDG_DATA_HOME or ~/.local/share by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("persist_ca")
+                .long("persist_ca")
+                .help("Keep the generated local CA across runs, its key is written next to --tls_ca with the .key extension and must stay private to the current user")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::new("upstream_ca")
+                .long("upstream_ca")
+                .value_name("PATH")
+                .help("PEM CA certificates to verify the server host certificate with instead of the system ones")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("insecure_upstream")
+                .long("insecure_upstream")
+                .help("Don't verify the server host certificate on the secure ports")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::new("udp_control_port")
                 .long("udp_control_port")
@@ -96,5 +155,129 @@ pub fn get_cli_args() -> ArgMatches {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::new("admin_port")
+                .long("admin_port")
+                .value_name("PORT")
+                .default_value("8767")
+                .help("Port for the HTTP admin API with JSON replies under /__admin")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("cassette")
+                .long("cassette")
+                .value_name("PATH")
+                .help("Cassette file to load recordings from at startup and save them to on stop")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("min_score")
+                .long("min_score")
+                .value_name("RATIO")
+                .default_value("0")
+                .help("Minimal ratio of matched n-grams to the request n-grams for a recording to be replayed")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("miss_policy")
+                .long("miss_policy")
+                .value_name("POLICY")
+                .default_value("clickhouse-error")
+                .possible_values(["clickhouse-error", "not-found", "default-body"])
+                .help("Answer for replayed requests without a good enough recording")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("miss_body")
+                .long("miss_body")
+                .value_name("TEXT")
+                .default_value("")
+                .help("Response body for the default-body miss policy")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("mask_literals")
+                .long("mask_literals")
+                .help("Match queries by fingerprints with numeric and string literals masked, literals only break ties")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::new("matcher")
+                .long("matcher")
+                .value_name("STRATEGY")
+                .default_value("ngrams")
+                .possible_values(MATCHERS)
+                .help("Strategy for matching replayed requests with recordings")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("ngram_size")
+                .long("ngram_size")
+                .value_name("N")
+                .default_value("3")
+                .help("Size of n-grams for the ngrams, jaccard and cosine matchers")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("matcher_config")
+                .long("matcher_config")
+                .value_name("FILE")
+                .help("JSON file like {\"matcher\": \"jaccard\", \"ngram_size\": 4}, --matcher and --ngram_size override it")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("sequential")
+                .long("sequential")
+                .value_name("POLICY")
+                .possible_values(["repeat-last", "error", "cycle"])
+                .help("Replay recordings of the same request in recorded order, each once; POLICY applies when they run out")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("replay_speed")
+                .long("replay_speed")
+                .value_name("FACTOR")
+                .help("Replay answers with the recorded response times sped up by FACTOR (1 for the original pacing), right away without it")
+                .conflicts_with("latency")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("latency")
+                .long("latency")
+                .value_name("SPEC")
+                .help("Replay answers with the recorded response times, SPEC like scale=1,jitter=0.1,min=10ms,max=5s adjusts them")
+                .takes_value(true)
+                .validator(|spec| spec.parse::<Latency>().map(|_| ()))
+                .required(false),
+        )
+        .arg(
+            Arg::new("latency_rule")
+                .long("latency_rule")
+                .value_names(&["PATTERN", "SPEC"])
+                .help("Latency SPEC for queries containing PATTERN case-insensitively instead of --latency, may be repeated, the first matching rule applies")
+                .takes_value(true)
+                .number_of_values(2)
+                .multiple_occurrences(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("server_version")
+                .long("server_version")
+                .value_name("VERSION")
+                .help("ClickHouse version like 23.8.1 to report to native clients in Replay state instead of the recorded one")
+                .takes_value(true)
+                .required(false),
+        )
         .get_matches()
 }
\ No newline at end of file
@@ -0,0 +1,585 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::native::{DecodeError, Reader};
+
+type Result<T> = std::result::Result<T, DecodeError>;
+
+/// Column type parsed from its ClickHouse name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnType {
+    /// Signed integer of the given size in bytes.
+    Int(usize),
+    UInt(usize),
+    Float32,
+    Float64,
+    Bool,
+    Decimal { size: usize, scale: u32 },
+    Date,
+    Date32,
+    DateTime,
+    /// Ticks of `10^-precision` seconds.
+    DateTime64(u32),
+    Uuid,
+    Ipv4,
+    Ipv6,
+    /// Enum8 or Enum16 with its value names.
+    Enum(usize, Vec<(i64, String)>),
+    String,
+    FixedString(usize),
+    Nothing,
+    Nullable(Box<ColumnType>),
+    Array(Box<ColumnType>),
+    Tuple(Vec<ColumnType>),
+    /// Keys and values, serialized as `Array(Tuple(K, V))`.
+    Map(Box<ColumnType>, Box<ColumnType>),
+    LowCardinality(Box<ColumnType>),
+}
+
+impl ColumnType {
+    pub fn parse(name: &str) -> Result<Self> {
+        let name = name.trim();
+        let (base, args) = match name.find('(') {
+            Some(open) if name.ends_with(')') => (&name[..open], split_type_args(&name[open + 1..name.len() - 1])),
+            _ => (name, Vec::new()),
+        };
+        let arg = |i: usize| -> Result<Self> {
+            args.get(i)
+                .ok_or_else(|| DecodeError::Invalid(format!("type {} has no argument {}", name, i)))
+                .and_then(|arg| Self::parse(arg))
+        };
+        let number_arg = |i: usize| -> Result<u32> {
+            args.get(i)
+                .and_then(|arg| arg.trim().parse().ok())
+                .ok_or_else(|| DecodeError::Invalid(format!("bad type {}", name)))
+        };
+
+        let column_type = match base {
+            "Int8" => ColumnType::Int(1),
+            "Int16" => ColumnType::Int(2),
+            "Int32" => ColumnType::Int(4),
+            "Int64" => ColumnType::Int(8),
+            "Int128" => ColumnType::Int(16),
+            "Int256" => ColumnType::Int(32),
+            "UInt8" => ColumnType::UInt(1),
+            "UInt16" => ColumnType::UInt(2),
+            "UInt32" => ColumnType::UInt(4),
+            "UInt64" => ColumnType::UInt(8),
+            "UInt128" => ColumnType::UInt(16),
+            "UInt256" => ColumnType::UInt(32),
+            base if base.starts_with("Interval") => ColumnType::Int(8),
+            "Float32" => ColumnType::Float32,
+            "Float64" => ColumnType::Float64,
+            "Bool" => ColumnType::Bool,
+            "Decimal" => {
+                let size = match number_arg(0)? {
+                    0..=9 => 4,
+                    10..=18 => 8,
+                    19..=38 => 16,
+                    _ => 32,
+                };
+                ColumnType::Decimal { size, scale: number_arg(1)? }
+            }
+            "Decimal32" => ColumnType::Decimal { size: 4, scale: number_arg(0)? },
+            "Decimal64" => ColumnType::Decimal { size: 8, scale: number_arg(0)? },
+            "Decimal128" => ColumnType::Decimal { size: 16, scale: number_arg(0)? },
+            "Decimal256" => ColumnType::Decimal { size: 32, scale: number_arg(0)? },
+            "Date" => ColumnType::Date,
+            "Date32" => ColumnType::Date32,
+            "DateTime" => ColumnType::DateTime,
+            "DateTime64" => ColumnType::DateTime64(number_arg(0)?),
+            "UUID" => ColumnType::Uuid,
+            "IPv4" => ColumnType::Ipv4,
+            "IPv6" => ColumnType::Ipv6,
+            "Enum8" => ColumnType::Enum(1, parse_enum_names(&args)),
+            "Enum16" => ColumnType::Enum(2, parse_enum_names(&args)),
+            "String" => ColumnType::String,
+            "FixedString" => ColumnType::FixedString(number_arg(0)? as usize),
+            "Nothing" => ColumnType::Nothing,
+            "Nullable" => ColumnType::Nullable(Box::new(arg(0)?)),
+            "Array" => ColumnType::Array(Box::new(arg(0)?)),
+            "LowCardinality" => ColumnType::LowCardinality(Box::new(arg(0)?)),
+            "SimpleAggregateFunction" => arg(1)?,
+            "Map" => ColumnType::Map(Box::new(arg(0)?), Box::new(arg(1)?)),
+            "Tuple" => ColumnType::Tuple(
+                args.iter()
+                    .map(|arg| Self::parse(strip_element_name(arg)))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            _ => return Err(DecodeError::Unsupported(format!("column type {}", name))),
+        };
+        Ok(column_type)
+    }
+
+    /// Size of a single value for types serialized as fixed-size values.
+    fn fixed_size(&self) -> Option<usize> {
+        match self {
+            ColumnType::Int(size) | ColumnType::UInt(size) | ColumnType::Enum(size, _) => Some(*size),
+            ColumnType::Decimal { size, .. } | ColumnType::FixedString(size) => Some(*size),
+            ColumnType::Bool | ColumnType::Nothing => Some(1),
+            ColumnType::Date => Some(2),
+            ColumnType::Float32 | ColumnType::Date32 | ColumnType::DateTime | ColumnType::Ipv4 => Some(4),
+            ColumnType::Float64 | ColumnType::DateTime64(_) => Some(8),
+            ColumnType::Uuid | ColumnType::Ipv6 => Some(16),
+            _ => None,
+        }
+    }
+
+    /// Serialization state written once before the column data, only LowCardinality has one.
+    pub fn skip_prefix(&self, r: &mut Reader) -> Result<()> {
+        match self {
+            ColumnType::LowCardinality(_) => { let _keys_version = r.u64()?; }
+            ColumnType::Nullable(nested) | ColumnType::Array(nested) => nested.skip_prefix(r)?,
+            ColumnType::Tuple(elements) => {
+                for element in elements {
+                    element.skip_prefix(r)?;
+                }
+            }
+            ColumnType::Map(keys, values) => {
+                keys.skip_prefix(r)?;
+                values.skip_prefix(r)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn skip_data(&self, r: &mut Reader, rows: u64) -> Result<()> {
+        if let Some(size) = self.fixed_size() {
+            return r.skip(rows.saturating_mul(size as u64));
+        }
+        match self {
+            ColumnType::String => {
+                for _ in 0..rows {
+                    let len = r.varint()?;
+                    r.skip(len)?;
+                }
+                Ok(())
+            }
+            ColumnType::Nullable(nested) => {
+                r.skip(rows)?;
+                nested.skip_data(r, rows)
+            }
+            ColumnType::Array(nested) => {
+                let offsets = read_offsets(r, rows)?;
+                nested.skip_data(r, offsets.last().copied().unwrap_or(0))
+            }
+            ColumnType::Map(keys, values) => {
+                let offsets = read_offsets(r, rows)?;
+                let total = offsets.last().copied().unwrap_or(0);
+                keys.skip_data(r, total)?;
+                values.skip_data(r, total)
+            }
+            ColumnType::Tuple(elements) => {
+                for element in elements {
+                    element.skip_data(r, rows)?;
+                }
+                Ok(())
+            }
+            ColumnType::LowCardinality(nested) => {
+                let index_size = read_low_cardinality_keys(r, nested, |r, dictionary, keys| dictionary.skip_data(r, keys))?;
+                let indexes = r.u64()?;
+                r.skip(indexes.saturating_mul(index_size))
+            }
+            _ => unreachable!("fixed-size type {:?}", self),
+        }
+    }
+
+    /// Decodes `rows` values of the column data, the prefix must have been read already.
+    pub fn read_values(&self, r: &mut Reader, rows: u64) -> Result<Vec<Value>> {
+        let mut values = Vec::new();
+        match self {
+            ColumnType::Nullable(nested) => {
+                let nulls = r.bytes(to_usize(rows)?)?;
+                let nested = nested.read_values(r, rows)?;
+                return Ok(nulls.iter()
+                    .zip(nested)
+                    .map(|(&null, value)| if null != 0 { Value::Null } else { value })
+                    .collect());
+            }
+            ColumnType::Array(nested) => {
+                let offsets = read_offsets(r, rows)?;
+                let mut elements = nested.read_values(r, offsets.last().copied().unwrap_or(0))?.into_iter();
+                let mut start = 0;
+                for end in offsets {
+                    values.push(Value::Array(elements.by_ref().take(to_usize(end - start)?).collect()));
+                    start = end;
+                }
+            }
+            ColumnType::Map(keys, map_values) => {
+                let offsets = read_offsets(r, rows)?;
+                let total = offsets.last().copied().unwrap_or(0);
+                let mut pairs = keys.read_values(r, total)?.into_iter().zip(map_values.read_values(r, total)?);
+                let mut start = 0;
+                for end in offsets {
+                    values.push(Value::Map(pairs.by_ref().take(to_usize(end - start)?).collect()));
+                    start = end;
+                }
+            }
+            ColumnType::Tuple(elements) => {
+                let mut columns = elements.iter()
+                    .map(|element| element.read_values(r, rows).map(Vec::into_iter))
+                    .collect::<Result<Vec<_>>>()?;
+                for _ in 0..rows {
+                    values.push(Value::Tuple(columns.iter_mut().filter_map(Iterator::next).collect()));
+                }
+            }
+            ColumnType::LowCardinality(nested) => {
+                let mut keys = Vec::new();
+                let index_size = read_low_cardinality_keys(r, nested, |r, dictionary, len| {
+                    keys = dictionary.read_values(r, len)?;
+                    Ok(())
+                })?;
+                let indexes = r.u64()?;
+                for _ in 0..indexes {
+                    let index = read_uint(r.bytes(index_size as usize)?) as usize;
+                    values.push(match nested.as_ref() {
+                        // The first key of a nullable dictionary stands for NULL.
+                        ColumnType::Nullable(_) if index == 0 => Value::Null,
+                        _ => keys.get(index).cloned().ok_or_else(|| DecodeError::Invalid(format!("index {} out of dictionary", index)))?,
+                    });
+                }
+            }
+            ColumnType::String => {
+                for _ in 0..rows {
+                    let len = r.varint()?;
+                    values.push(Value::String(String::from_utf8_lossy(r.bytes(to_usize(len)?)?).into_owned()));
+                }
+            }
+            _ => {
+                let size = self.fixed_size().unwrap();
+                for _ in 0..rows {
+                    values.push(self.fixed_value(r.bytes(size)?)?);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    fn fixed_value(&self, bytes: &[u8]) -> Result<Value> {
+        Ok(match self {
+            ColumnType::Int(size) | ColumnType::UInt(size) if *size > 16 => Value::Bytes(bytes.to_vec()),
+            ColumnType::Int(_) => Value::Int(read_int(bytes)),
+            ColumnType::UInt(_) => Value::UInt(read_uint(bytes)),
+            ColumnType::Float32 => Value::Float(f32::from_le_bytes(bytes.try_into().unwrap()).into()),
+            ColumnType::Float64 => Value::Float(f64::from_le_bytes(bytes.try_into().unwrap())),
+            ColumnType::Bool => Value::Bool(bytes[0] != 0),
+            ColumnType::Decimal { size, .. } if *size > 16 => Value::Bytes(bytes.to_vec()),
+            ColumnType::Decimal { scale, .. } => Value::Text(format_decimal(read_int(bytes), *scale)),
+            ColumnType::Date => Value::Text(format_date(read_uint(bytes) as i64)),
+            ColumnType::Date32 => Value::Text(format_date(read_int(bytes) as i64)),
+            ColumnType::DateTime => Value::Text(format_date_time(read_uint(bytes) as i64, 0, 0)),
+            ColumnType::DateTime64(precision) => {
+                let ticks = read_int(bytes) as i64;
+                let scale = 10i64.checked_pow(*precision).ok_or_else(|| DecodeError::Invalid(format!("DateTime64 precision {} too big", precision)))?;
+                Value::Text(format_date_time(ticks.div_euclid(scale), ticks.rem_euclid(scale), *precision))
+            }
+            ColumnType::Uuid => {
+                let hex = format!("{:016x}{:016x}", read_uint(&bytes[..8]), read_uint(&bytes[8..]));
+                Value::Text(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+            }
+            ColumnType::Ipv4 => Value::Text(Ipv4Addr::from(read_uint(bytes) as u32).to_string()),
+            ColumnType::Ipv6 => Value::Text(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()).to_string()),
+            ColumnType::Enum(_, names) => {
+                let value = read_int(bytes) as i64;
+                match names.iter().find(|(n, _)| *n == value) {
+                    Some((_, name)) => Value::String(name.clone()),
+                    None => Value::Int(value.into()),
+                }
+            }
+            ColumnType::FixedString(_) => {
+                let end = bytes.iter().rposition(|&b| b != 0).map(|i| i + 1).unwrap_or(0);
+                Value::String(String::from_utf8_lossy(&bytes[..end]).into_owned())
+            }
+            ColumnType::Nothing => Value::Null,
+            _ => Value::Bytes(bytes.to_vec()),
+        })
+    }
+
+    /// Dictionary of `LowCardinality(Nullable(T))` keeps plain `T` keys.
+    fn dictionary_type(&self) -> &ColumnType {
+        match self {
+            ColumnType::Nullable(nested) => nested,
+            other => other,
+        }
+    }
+}
+
+/// Reads the LowCardinality dictionary with `read_keys`, returns the size of the indexes that follow it.
+fn read_low_cardinality_keys<F>(r: &mut Reader, nested: &ColumnType, mut read_keys: F) -> Result<u64>
+where
+    F: FnMut(&mut Reader, &ColumnType, u64) -> Result<()>,
+{
+    const HAS_ADDITIONAL_KEYS: u64 = 1 << 9;
+    let index_type = r.u64()?;
+    if index_type & 0xff > 3 {
+        return Err(DecodeError::Invalid(format!("bad LowCardinality index type {}", index_type)));
+    }
+    if index_type & HAS_ADDITIONAL_KEYS != 0 {
+        let keys = r.u64()?;
+        read_keys(r, nested.dictionary_type(), keys)?;
+    }
+    Ok(1 << (index_type & 0xff))
+}
+
+/// Array offsets are cumulative, the last one is the number of nested elements.
+fn read_offsets(r: &mut Reader, rows: u64) -> Result<Vec<u64>> {
+    let mut offsets = Vec::new();
+    for _ in 0..rows {
+        let offset = r.u64()?;
+        if offset < offsets.last().copied().unwrap_or(0) {
+            return Err(DecodeError::Invalid("array offsets go backwards".to_string()));
+        }
+        offsets.push(offset);
+    }
+    Ok(offsets)
+}
+
+fn to_usize(len: u64) -> Result<usize> {
+    usize::try_from(len).map_err(|_| DecodeError::Invalid("too long".to_string()))
+}
+
+/// Little-endian integer of up to 16 bytes.
+fn read_uint(bytes: &[u8]) -> u128 {
+    bytes.iter().rev().fold(0, |value, &b| value << 8 | u128::from(b))
+}
+
+fn read_int(bytes: &[u8]) -> i128 {
+    let unused = 128 - 8 * bytes.len() as u32;
+    ((read_uint(bytes) << unused) as i128) >> unused
+}
+
+fn format_decimal(value: i128, scale: u32) -> String {
+    if scale == 0 {
+        return value.to_string();
+    }
+    let digits = format!("{:0width$}", value.unsigned_abs(), width = scale as usize + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale as usize);
+    format!("{}{}.{}", if value < 0 { "-" } else { "" }, int, frac)
+}
+
+/// Days since 1970-01-01 to a calendar date.
+fn format_date(days: i64) -> String {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Seconds since the epoch in UTC, column time zones are not applied.
+fn format_date_time(seconds: i64, fraction: i64, precision: u32) -> String {
+    let time = seconds.rem_euclid(86_400);
+    let mut s = format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(seconds.div_euclid(86_400)),
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+    );
+    if precision > 0 {
+        s.push_str(&format!(".{:0width$}", fraction, width = precision as usize));
+    }
+    s
+}
+
+fn parse_enum_names(args: &[&str]) -> Vec<(i64, String)> {
+    args.iter()
+        .filter_map(|arg| {
+            let (name, value) = arg.rsplit_once('=')?;
+            let name = name.trim().strip_prefix('\'')?.strip_suffix('\'')?;
+            Some((value.trim().parse().ok()?, name.replace("\\'", "'")))
+        })
+        .collect()
+}
+
+/// Splits type arguments on top-level commas, respecting nested parentheses and quotes.
+fn split_type_args(args: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    let bytes = args.as_bytes();
+    for (i, &c) in bytes.iter().enumerate() {
+        match c {
+            b'\'' if i == 0 || bytes[i - 1] != b'\\' => quoted = !quoted,
+            b'(' if !quoted => depth += 1,
+            b')' if !quoted => depth -= 1,
+            b',' if !quoted && depth == 0 => {
+                result.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !args[start..].trim().is_empty() {
+        result.push(args[start..].trim());
+    }
+    result
+}
+
+/// Named tuple elements look like `name Type`, the type starts after the first space outside parentheses.
+fn strip_element_name(element: &str) -> &str {
+    match element.find([' ', '(']) {
+        Some(i) if element.as_bytes()[i] == b' ' => element[i + 1..].trim(),
+        _ => element,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i128),
+    UInt(u128),
+    Float(f64),
+    String(String),
+    /// Already formatted decimals, dates, UUIDs and addresses.
+    Text(String),
+    /// Values we can't interpret, shown as hex.
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+    Tuple(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// Table cell text, top-level strings are not quoted.
+    pub fn cell(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn join<T>(f: &mut fmt::Formatter<'_>, items: &[T], item: impl Fn(&mut fmt::Formatter<'_>, &T) -> fmt::Result) -> fmt::Result {
+            for (i, value) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                item(f, value)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::UInt(u) => write!(f, "{}", u),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::String(s) => write!(f, "'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bytes(bytes) => write!(f, "{}", hex(bytes)),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                join(f, values, |f, v| write!(f, "{}", v))?;
+                write!(f, "]")
+            }
+            Value::Tuple(values) => {
+                write!(f, "(")?;
+                join(f, values, |f, v| write!(f, "{}", v))?;
+                write!(f, ")")
+            }
+            Value::Map(pairs) => {
+                write!(f, "{{")?;
+                join(f, pairs, |f, (k, v)| write!(f, "{}: {}", k, v))?;
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + 2 * bytes.len());
+    s.push_str("0x");
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(type_name: &str, rows: u64, data: &[u8]) -> Vec<String> {
+        let column_type = ColumnType::parse(type_name).unwrap();
+        let mut r = Reader::new(data);
+        column_type.skip_prefix(&mut r).unwrap();
+        let values = column_type.read_values(&mut r, rows).unwrap();
+        assert_eq!(r.position(), data.len(), "{}", type_name);
+        values.iter().map(Value::cell).collect()
+    }
+
+    #[test]
+    fn column_types() {
+        assert_eq!(ColumnType::parse("Decimal(20, 4)").unwrap(), ColumnType::Decimal { size: 16, scale: 4 });
+        assert_eq!(ColumnType::parse("FixedString(3)").unwrap(), ColumnType::FixedString(3));
+        assert_eq!(
+            ColumnType::parse("Map(String, Array(Nullable(UInt8)))").unwrap(),
+            ColumnType::Map(Box::new(ColumnType::String), Box::new(ColumnType::Array(Box::new(ColumnType::Nullable(Box::new(ColumnType::UInt(1))))))),
+        );
+        assert_eq!(
+            ColumnType::parse("Tuple(a LowCardinality(String), b Enum8('x' = 1, 'y,z' = 2))").unwrap(),
+            ColumnType::Tuple(vec![
+                ColumnType::LowCardinality(Box::new(ColumnType::String)),
+                ColumnType::Enum(1, vec![(1, "x".to_string()), (2, "y,z".to_string())]),
+            ]),
+        );
+        assert!(matches!(ColumnType::parse("Object('json')"), Err(DecodeError::Unsupported(_))));
+    }
+
+    #[test]
+    fn fixed_size_values() {
+        assert_eq!(values("Int16", 2, &[0xfe, 0xff, 7, 0]), ["-2", "7"]);
+        assert_eq!(values("Decimal(9, 2)", 2, &[0x39, 0x30, 0, 0, 0xfb, 0xff, 0xff, 0xff]), ["123.45", "-0.05"]);
+        assert_eq!(values("Date", 1, &[0x46, 0x4d]), ["2024-02-29"]);
+        assert_eq!(values("DateTime64(3)", 1, &1_709_164_800_123u64.to_le_bytes()), ["2024-02-29 00:00:00.123"]);
+        assert_eq!(values("IPv4", 1, &[1, 0, 0, 127]), ["127.0.0.1"]);
+        assert_eq!(values("Enum8('x' = 1, 'y' = 2)", 2, &[2, 3]), ["y", "3"]);
+        assert_eq!(values("FixedString(3)", 1, b"ab\0"), ["ab"]);
+
+        let column_type = ColumnType::parse("DateTime64(19)").unwrap();
+        assert!(matches!(column_type.read_values(&mut Reader::new(&[0; 8]), 1), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn nested_values() {
+        // Null map, then the nested values including the ones under NULL.
+        assert_eq!(values("Nullable(UInt8)", 2, &[0, 1, 5, 0]), ["5", "NULL"]);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(&[1, b'a', 1, b'b', 2, b'c', b'\'']);
+        assert_eq!(values("Array(String)", 2, &data), ["['a']", "['b', 'c\\'']"]);
+
+        // Key and value columns of a single map with two pairs.
+        let mut data = 2u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&[1, b'k', 1, b'l', 1, 2]);
+        assert_eq!(values("Map(String, UInt8)", 1, &data), ["{'k': 1, 'l': 2}"]);
+    }
+
+    #[test]
+    fn low_cardinality_values() {
+        let mut data = 1u64.to_le_bytes().to_vec();
+        // Index type UInt8 with additional keys, a nullable dictionary starts with the NULL key.
+        data.extend_from_slice(&(1u64 << 9).to_le_bytes());
+        data.extend_from_slice(&2u64.to_le_bytes());
+        data.extend_from_slice(&[0, 1, b'a']);
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 1]);
+        assert_eq!(values("LowCardinality(Nullable(String))", 3, &data), ["a", "NULL", "a"]);
+    }
+}
@@ -1,4 +1,5 @@
 use log::{self, info, debug, error};
+use std::net::SocketAddr;
 use tokio::{
     io,
     net::UdpSocket,
@@ -6,10 +7,17 @@ use tokio::{
     sync::mpsc::{self, Sender},
 };
 
-use crate::appguts::AppGuts;
+use crate::{
+    appguts::{AppGuts, Protocol, State, UnsafeAppGuts},
+    edit::{self, ResponseEdit},
+    listing::{self, Listing},
+};
+
+/// Largest UDP payload, longer commands would be truncated and longer replies are cut.
+const MAX_COMMAND_SIZE: usize = 65507;
 
 pub async fn start_udp_handler(port: &str, guts: AppGuts) -> io::Result<()> {
-    let (commands_sender, mut commands_receiver) = mpsc::channel::<String>(16);
+    let (commands_sender, mut commands_receiver) = mpsc::channel::<(String, SocketAddr)>(16);
 
     let addr = format!("localhost:{}", &port);
     let control = UdpSocket::bind(&addr).await?;
@@ -21,17 +29,97 @@ pub async fn start_udp_handler(port: &str, guts: AppGuts) -> io::Result<()> {
         select!{
             Ok(()) = act(&control, commands_sender.clone()) => {
             }
-            Some(command) = commands_receiver.recv() => {
+            Some((command, admin_address)) = commands_receiver.recv() => {
                 if command == "stop" {
+                    control.send_to(b"Ack\n", admin_address).await?;
                     break;
                 } else if command == "change state" {
-                    let mut guts = guts.lock().unwrap();
-                    guts.change_state();
+                    guts.lock().unwrap().change_state();
+                    control.send_to(b"Ack\n", admin_address).await?;
+                } else if let Some(args) = command.strip_prefix("set ") {
+                    let reply = match parse_set(args) {
+                        Ok((state, protocol)) => {
+                            let mut guts = guts.lock().unwrap();
+                            match guts.set_state(protocol, state) {
+                                Ok(()) => format!("State: {}\n", states(&guts)),
+                                Err(e) => format!("Error: {}\n", e),
+                            }
+                        }
+                        Err(e) => format!("Error: {}\n", e),
+                    };
+                    info!("{}", reply.trim_end());
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if command == "get state" {
+                    let reply = format!("State: {}\n", states(&guts.lock().unwrap()));
+                    info!("{}", reply.trim_end());
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if command == "rewind" {
+                    guts.lock().unwrap().rewind();
+                    control.send_to(b"Ack\n", admin_address).await?;
                 } else if command == "show db" {
-                    let guts = guts.lock().unwrap();
-                    guts.show_data();
+                    guts.lock().unwrap().show_data();
+                    control.send_to(b"Ack\n", admin_address).await?;
+                } else if command == "list" || command.starts_with("list ") {
+                    let reply = match list(&guts.lock().unwrap(), &command["list".len()..]) {
+                        Ok(reply) => reply,
+                        Err(e) => format!("Error: {}\n", e),
+                    };
+                    control.send_to(fit_datagram(reply).as_bytes(), admin_address).await?;
+                } else if command == "clear" {
+                    guts.lock().unwrap().reset();
+                    control.send_to(b"Cleared\n", admin_address).await?;
+                } else if let Some(args) = command.strip_prefix("delete ") {
+                    let reply = reply(delete(&mut guts.lock().unwrap(), args));
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if let Some(args) = command.strip_prefix("edit ") {
+                    let reply = reply(edit(&mut guts.lock().unwrap(), args));
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if let Some(args) = command.strip_prefix("insert ") {
+                    let reply = reply(insert(&mut guts.lock().unwrap(), args));
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if let Some(args) = command.strip_prefix("move ") {
+                    let reply = reply(move_entry(&mut guts.lock().unwrap(), args));
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if command == "show stats" {
+                    let reply = {
+                        let guts = guts.lock().unwrap();
+                        let stats = guts.lookup_stats();
+                        format!("Lookups: {}, fast path hits: {}\n", stats.lookups, stats.fast_path_hits)
+                    };
+                    info!("{}", reply.trim_end());
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if let Some(idx) = command.strip_prefix("show ") {
+                    let reply = match idx.trim().parse::<usize>() {
+                        Ok(idx) => match guts.lock().unwrap().entry(idx) {
+                            Some(data) => listing::describe(idx, data),
+                            None => format!("Error: no entry {}\n", idx),
+                        },
+                        Err(_) => format!("Error: expected show db|stats|<index>, got {:?}\n", idx),
+                    };
+                    control.send_to(fit_datagram(reply).as_bytes(), admin_address).await?;
+                } else if let Some(query) = command.strip_prefix("compare ") {
+                    let reply = compare(&guts.lock().unwrap(), query);
+                    info!("{}", reply.trim_end());
+                    control.send_to(fit_datagram(reply).as_bytes(), admin_address).await?;
+                } else if let Some(path) = command.strip_prefix("save ") {
+                    let path = path.trim();
+                    let reply = match guts.lock().unwrap().save_cassette(path) {
+                        Ok(len) => format!("Saved {} entries to {}\n", len, path),
+                        Err(e) => format!("Error: failed to save {}: {}\n", path, e),
+                    };
+                    info!("{}", reply.trim_end());
+                    control.send_to(reply.as_bytes(), admin_address).await?;
+                } else if let Some(path) = command.strip_prefix("load ") {
+                    let path = path.trim();
+                    let reply = match guts.lock().unwrap().load_cassette(path) {
+                        Ok(len) => format!("Loaded {} entries from {}\n", len, path),
+                        Err(e) => format!("Error: failed to load {}: {}\n", path, e),
+                    };
+                    info!("{}", reply.trim_end());
+                    control.send_to(reply.as_bytes(), admin_address).await?;
                 } else {
-                    error!("invalid command: {}", command)
+                    error!("invalid command: {}", command);
+                    control.send_to(fit_datagram(format!("Error: invalid command {:?}\n", command)).as_bytes(), admin_address).await?;
                 }
             }
         }
@@ -40,17 +128,200 @@ pub async fn start_udp_handler(port: &str, guts: AppGuts) -> io::Result<()> {
     Ok(())
 }
 
-async fn act(control: &UdpSocket, commands_sender: Sender<String>) -> io::Result<()> {
-    let mut buf = vec![0u8; 256];
+/// Summary table of the entries matching the `key=value` options of a `list` command.
+fn list(guts: &UnsafeAppGuts, args: &str) -> Result<String, String> {
+    let options = listing::split_options(args)?;
+    let listing = Listing::from_options(options.iter().map(|(key, value)| (key.as_str(), value.as_str())))?;
+    let (page, total) = listing.page(guts.entries());
+    let mut reply = listing::table(&page, listing.width);
+    reply.push_str(&format!("{} of {} matching entries from offset {}\n", page.len(), total, listing.offset));
+    Ok(reply)
+}
+
+fn as_pairs(options: &[(String, String)]) -> impl Iterator<Item = (&str, &str)> {
+    options.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+}
+
+fn parse_index(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("expected an entry index, got {:?}", value))
+}
+
+/// Reply line of an edit command.
+fn reply(result: Result<String, String>) -> String {
+    let reply = match result {
+        Ok(reply) => format!("{}\n", reply),
+        Err(e) => format!("Error: {}\n", e),
+    };
+    info!("{}", reply.trim_end());
+    reply
+}
+
+/// `delete <index>` or `delete key=value ...` with the filter options of `list`.
+fn delete(guts: &mut UnsafeAppGuts, args: &str) -> Result<String, String> {
+    let args = args.trim();
+    if let Ok(idx) = args.parse::<usize>() {
+        return match guts.delete_entry(idx) {
+            Some(_) => Ok(format!("Deleted entry {}", idx)),
+            None => Err(format!("no entry {}", idx)),
+        };
+    }
+    let options = listing::split_options(args)?;
+    let filter = Listing::from_options(as_pairs(&options))?.filter;
+    if filter.is_empty() {
+        return Err("expected an entry index or filter options, use clear to delete everything".to_string());
+    }
+    Ok(format!("Deleted {} entries", guts.delete_entries(&filter)))
+}
+
+/// `edit <index> status=CODE body=TEXT header="Name: value" ...`
+fn edit(guts: &mut UnsafeAppGuts, args: &str) -> Result<String, String> {
+    let (idx, args) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    let idx = parse_index(idx)?;
+    let mut edit = ResponseEdit::default();
+    for (key, value) in listing::split_options(args)? {
+        edit.set(&key, &value)?;
+    }
+    if edit.is_empty() {
+        return Err("expected status, body or header options".to_string());
+    }
+    guts.edit_entry(idx, &edit)?;
+    Ok(format!("Edited entry {}", idx))
+}
+
+/// `insert query=TEXT [index=N method=GET path=/ status=CODE body=TEXT header="Name: value" ...]`
+fn insert(guts: &mut UnsafeAppGuts, args: &str) -> Result<String, String> {
+    let options = listing::split_options(args)?;
+    let (idx, data) = edit::handwritten_entry(as_pairs(&options))?;
+    Ok(format!("Inserted entry {}", guts.insert_entry(idx, data)))
+}
+
+/// `move <from> <to>`
+fn move_entry(guts: &mut UnsafeAppGuts, args: &str) -> Result<String, String> {
+    let (from, to) = args.trim().split_once(' ').ok_or("expected move <from> <to>")?;
+    let (from, to) = (parse_index(from)?, parse_index(to.trim())?);
+    guts.move_entry(from, to)?;
+    Ok(format!("Moved entry {} to {}", from, to))
+}
+
+/// Best entry of every matcher for `query`, one line each.
+fn compare(guts: &UnsafeAppGuts, query: &str) -> String {
+    guts.compare_matchers(query.to_string()).into_iter()
+        .map(|(name, best)| match best {
+            Some((idx, score)) => format!("{}: entry {} with score {:.3}\n", name, idx, score),
+            None => format!("{}: no match\n", name),
+        })
+        .collect()
+}
+
+/// Cuts replies longer than a UDP datagram, the admin API has no such limit.
+fn fit_datagram(mut reply: String) -> String {
+    const TRUNCATED: &str = "\n... truncated\n";
+    if reply.len() > MAX_COMMAND_SIZE {
+        let mut end = MAX_COMMAND_SIZE - TRUNCATED.len();
+        while !reply.is_char_boundary(end) {
+            end -= 1;
+        }
+        reply.truncate(end);
+        reply.push_str(TRUNCATED);
+    }
+    reply
+}
+
+/// `<state> [http|native]` of a `set` command, no protocol sets both.
+fn parse_set(args: &str) -> Result<(State, Option<Protocol>), String> {
+    let mut args = args.split_whitespace();
+    let state = args.next().ok_or("expected set record|replay|passthrough|replay-or-record [http|native]")?.parse()?;
+    let protocol = args.next().map(str::parse).transpose()?;
+    if args.next().is_some() {
+        return Err("expected set record|replay|passthrough|replay-or-record [http|native]".to_string());
+    }
+    Ok((state, protocol))
+}
+
+/// States of all protocols and the number of new recordings like `http=record native=replay new_recordings=3`.
+fn states(guts: &UnsafeAppGuts) -> String {
+    Protocol::ALL.iter()
+        .map(|protocol| format!("{}={} ", protocol, guts.state(*protocol)))
+        .chain([format!("new_recordings={}", guts.new_recordings())])
+        .collect()
+}
+
+async fn act(control: &UdpSocket, commands_sender: Sender<(String, SocketAddr)>) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_COMMAND_SIZE];
     let (len, admin_address) = control.recv_from(&mut buf).await?;
-    buf.resize(len, 0);
+    buf.truncate(len);
 
-    let command = String::from_utf8(buf).unwrap();
+    let command = match String::from_utf8(buf) {
+        Ok(command) => command,
+        Err(_) => {
+            error!("Command from {:?} is not valid UTF-8", admin_address);
+            control.send_to(b"Error: command is not valid UTF-8\n", admin_address).await?;
+            return Ok(());
+        }
+    };
     debug!("Got command {:?} from {:?}", &command, admin_address);
 
-    control.send_to(b"Ack\n", admin_address).await?;
-
-    commands_sender.send(command.trim().into()).await.unwrap();
+    commands_sender.send((command.trim().into(), admin_address)).await.unwrap();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{appguts::ReplayConfig, matcher::MATCHERS, ngrams::MiddlewareDataHttp};
+    use actix_web::http::{header::HeaderMap, StatusCode};
+    use bytes::Bytes;
+
+    #[test]
+    fn set_commands() {
+        assert_eq!(parse_set("replay").unwrap(), (State::Replay, None));
+        assert_eq!(parse_set(" passthrough  native ").unwrap(), (State::Passthrough, Some(Protocol::Native)));
+        assert_eq!(parse_set("record http").unwrap(), (State::Record, Some(Protocol::Http)));
+        assert_eq!(parse_set("replay tcp").unwrap_err(), "unknown protocol: tcp");
+        assert_eq!(parse_set("paused").unwrap_err(), "unknown state: paused");
+        assert!(parse_set("").is_err());
+        assert!(parse_set("replay http native").is_err());
+    }
+
+    #[test]
+    fn states_reply() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        guts.set_state(Some(Protocol::Native), State::Replay).unwrap();
+        assert_eq!(states(&guts), "http=record native=replay new_recordings=0");
+    }
+
+    #[test]
+    fn list_command() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        for query in ["select 1", "select * from system.tables", "select 2"] {
+            let http = MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new());
+            guts.insert_data(query.to_string(), None, Bytes::from_static(b"1\n"), Some(http), None);
+        }
+        let reply = list(&guts, r#" regex="^select \d" limit=1 offset=1"#).unwrap();
+        let lines = reply.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{}", reply);
+        assert!(lines[1].trim_start().starts_with("2 ") && lines[1].ends_with("select 2"), "{}", reply);
+        assert_eq!(lines[2], "1 of 2 matching entries from offset 1");
+        assert_eq!(list(&guts, "limit").unwrap_err(), "expected key=value, got \"limit\"");
+    }
+
+    #[test]
+    fn compare_command() {
+        let mut guts = UnsafeAppGuts::new(ReplayConfig::default());
+        assert!(compare(&guts, "select 1").lines().all(|line| line.ends_with(": no match")));
+        let http = MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new());
+        guts.insert_data("select 1".to_string(), None, Bytes::from_static(b"1\n"), Some(http), None);
+        let reply = compare(&guts, "select 1");
+        assert_eq!(reply.lines().count(), MATCHERS.len());
+        assert!(reply.starts_with("exact: entry 0 with score 1.000\n"), "{}", reply);
+    }
+
+    #[test]
+    fn long_replies_fit_a_datagram() {
+        assert_eq!(fit_datagram("short\n".to_string()), "short\n");
+        let reply = fit_datagram("é".repeat(MAX_COMMAND_SIZE));
+        assert!(reply.len() <= MAX_COMMAND_SIZE);
+        assert!(reply.ends_with("\n... truncated\n"));
+    }
+}
@@ -0,0 +1,207 @@
+use actix_web::http::{
+    Method,
+    StatusCode,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+use bytes::Bytes;
+use std::time::SystemTime;
+
+use crate::ngrams::{MiddlewareData, MiddlewareDataHttp, MiddlewareDataRequest};
+
+/// Changes to the answer of a recording.
+#[derive(Debug, Default)]
+pub struct ResponseEdit {
+    pub status: Option<StatusCode>,
+    pub body: Option<Bytes>,
+    /// Headers to set, `None` removes the header.
+    pub headers: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl ResponseEdit {
+    /// Options are `status=CODE`, `body=TEXT` and `header="Name: value"`, a header without a value is removed.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "status" => self.status = Some(parse_status(value)?),
+            "body" => self.body = Some(Bytes::copy_from_slice(value.as_bytes())),
+            "header" => self.headers.push(parse_header(value)?),
+            _ => return Err(format!("unknown option {:?}", key)),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none() && self.body.is_none() && self.headers.is_empty()
+    }
+
+    /// The recording with the changed answer, only HTTP recordings can be edited:
+    /// native answers are packets of the recorded protocol, not a body that could be replaced with text.
+    /// A new body drops the recorded chunk timings and updates a recorded `Content-Length`.
+    pub fn apply(&self, data: MiddlewareData) -> Result<MiddlewareData, String> {
+        let body = self.body.clone().unwrap_or_else(|| data.response().clone());
+        let http = match data.http() {
+            Some(http) => {
+                let (status, mut headers) = http.split();
+                if self.body.is_some() && headers.contains_key(header::CONTENT_LENGTH) {
+                    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                }
+                apply_headers(&mut headers, &self.headers);
+                let edited = MiddlewareDataHttp::new(self.status.unwrap_or(status), headers);
+                Some(match self.body {
+                    Some(_) => edited,
+                    None => edited.with_chunks(http.chunks().to_vec()),
+                })
+            }
+            None if !self.is_empty() => {
+                return Err("native recordings have no editable status, headers or body".to_string());
+            }
+            None => None,
+        };
+        Ok(data.with_response(body, http))
+    }
+}
+
+/// Hand-written HTTP recording from `query` (required), `method` (POST), `path` (/), `status` (200),
+/// `body` and `header` options. Returns it with the `index` option, the position to insert it at.
+/// POST queries are expected in the request body, others in the `query` URL parameter, the way clients send them.
+pub fn handwritten_entry<'a>(options: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<(Option<usize>, MiddlewareData), String> {
+    let mut index = None;
+    let mut query = None;
+    let mut method = Method::POST;
+    let mut path = "/".to_string();
+    let mut answer = ResponseEdit::default();
+    for (key, value) in options {
+        match key {
+            "index" => index = Some(value.parse().map_err(|e| format!("invalid index {:?}, {}", value, e))?),
+            "query" => query = Some(value.to_string()),
+            "method" => method = Method::from_bytes(value.to_uppercase().as_bytes()).map_err(|e| format!("invalid method {:?}, {}", value, e))?,
+            "path" => path = value.to_string(),
+            _ => answer.set(key, value)?,
+        }
+    }
+    let query = query.ok_or("expected a query option")?;
+
+    let (params, request_body) = if method == Method::POST {
+        (Vec::new(), Bytes::from(query.clone()))
+    } else {
+        (vec![("query".to_string(), query.clone())], Bytes::new())
+    };
+    let meta = MiddlewareDataRequest::new(method, path, params, &HeaderMap::new(), request_body);
+
+    let mut headers = HeaderMap::new();
+    apply_headers(&mut headers, &answer.headers);
+    let http = MiddlewareDataHttp::new(answer.status.unwrap_or(StatusCode::OK), headers);
+    let data = MiddlewareData::new(query, Some(meta), answer.body.unwrap_or_default(), Some(http))
+        .with_recorded_at(Some(SystemTime::now()));
+    Ok((index, data))
+}
+
+fn apply_headers(headers: &mut HeaderMap, changes: &[(HeaderName, Option<HeaderValue>)]) {
+    for (name, value) in changes {
+        match value {
+            Some(value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            None => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+fn parse_status(value: &str) -> Result<StatusCode, String> {
+    let code = value.parse::<u16>().map_err(|e| format!("invalid status {:?}, {}", value, e))?;
+    StatusCode::from_u16(code).map_err(|e| format!("invalid status {:?}, {}", value, e))
+}
+
+/// `Name: value`, or `Name:` to remove the header.
+fn parse_header(value: &str) -> Result<(HeaderName, Option<HeaderValue>), String> {
+    let (name, value) = value.split_once(':').ok_or(format!("expected \"Name: value\", got {:?}", value))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| format!("invalid header name {:?}, {}", name, e))?;
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok((name, None));
+    }
+    let value = HeaderValue::from_str(value).map_err(|e| format!("invalid header value {:?}, {}", value, e))?;
+    Ok((name, Some(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngrams::{MiddlewareDataNative, ResponseChunk};
+    use std::time::Duration;
+
+    fn edit(options: &[(&str, &str)]) -> ResponseEdit {
+        let mut edit = ResponseEdit::default();
+        for (key, value) in options {
+            edit.set(key, value).unwrap();
+        }
+        edit
+    }
+
+    fn recorded() -> MiddlewareData {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(2));
+        headers.insert(HeaderName::from_static("x-clickhouse-format"), HeaderValue::from_static("TSV"));
+        let chunks = vec![ResponseChunk { at: Duration::from_millis(5), len: 2 }];
+        let http = MiddlewareDataHttp::new(StatusCode::OK, headers).with_chunks(chunks);
+        MiddlewareData::new("select 1".to_string(), None, Bytes::from_static(b"1\n"), Some(http))
+    }
+
+    #[test]
+    fn http_edits() {
+        let edited = edit(&[("status", "503"), ("header", "X-ClickHouse-Format:"), ("header", "X-Test: yes")]).apply(recorded()).unwrap();
+        let (status, headers) = edited.http().unwrap().split();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!headers.contains_key("x-clickhouse-format"));
+        assert_eq!(headers.get("x-test").unwrap(), "yes");
+        assert_eq!(edited.response().as_ref(), b"1\n");
+        assert_eq!(edited.http().unwrap().chunks().len(), 1);
+
+        let edited = edit(&[("body", "42\n")]).apply(recorded()).unwrap();
+        assert_eq!(edited.response().as_ref(), b"42\n");
+        assert_eq!(edited.http().unwrap().split().1.get(header::CONTENT_LENGTH).unwrap(), "3");
+        assert!(edited.http().unwrap().chunks().is_empty());
+        assert_eq!(edited.request(), "select 1");
+    }
+
+    #[test]
+    fn native_answers_are_not_editable() {
+        let data = MiddlewareData::new_native("select 1".to_string(), Bytes::from_static(b"\x01"), MiddlewareDataNative { revision: 54460, compression: false });
+        for (key, value) in [("status", "500"), ("header", "X-Test: 1"), ("body", "text")] {
+            assert!(edit(&[(key, value)]).apply(data.clone()).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn bad_edits() {
+        let mut edit = ResponseEdit::default();
+        assert!(edit.is_empty());
+        for (key, value) in [("status", "2000"), ("status", "ok"), ("header", "no colon"), ("header", "bad name: 1"), ("speed", "1")] {
+            assert!(edit.set(key, value).is_err(), "{}={}", key, value);
+        }
+        assert!(edit.is_empty());
+    }
+
+    #[test]
+    fn handwritten_entries() {
+        let (index, data) = handwritten_entry([("query", "select 1"), ("body", "1\n"), ("index", "3")]).unwrap();
+        assert_eq!(index, Some(3));
+        let meta = data.request_meta().unwrap();
+        assert_eq!((meta.method(), meta.body().as_ref()), (&Method::POST, &b"select 1"[..]));
+        assert!(meta.query().is_empty());
+        assert_eq!(data.http().unwrap().split().0, StatusCode::OK);
+        assert_eq!(data.response().as_ref(), b"1\n");
+        assert!(data.recorded_at().is_some());
+
+        let (index, data) = handwritten_entry([("query", "select 2"), ("method", "get"), ("path", "/ping"), ("status", "404")]).unwrap();
+        assert_eq!(index, None);
+        let meta = data.request_meta().unwrap();
+        assert_eq!((meta.method(), meta.path()), (&Method::GET, "/ping"));
+        assert_eq!(meta.query(), [("query".to_string(), "select 2".to_string())]);
+        assert_eq!(data.http().unwrap().split().0, StatusCode::NOT_FOUND);
+
+        assert_eq!(handwritten_entry([("body", "1")]).unwrap_err(), "expected a query option");
+        assert!(handwritten_entry([("query", "select 1"), ("index", "first")]).is_err());
+    }
+}
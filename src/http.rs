@@ -2,23 +2,35 @@ use actix_web::{
     error::{self, PayloadError}, 
     web, App, Error, HttpRequest, HttpResponse, HttpServer,
 };
-use awc::Client;
+use awc::{Client, Connector};
 use futures::Stream;
 use futures_util::stream::{self, StreamExt};
-use log::{info, debug};
-use num_cpus;
-use std::{cmp, future, pin::Pin};
-use tokio::io;
+use log::{info, debug, warn};
+use std::{cell::RefCell, cmp, future, pin::Pin, rc::Rc, time::{Duration, Instant}};
+use tokio::{io, time};
 use url::Url;
 
-use crate::{mymiddleware::Logging, appguts::AppGuts, ngrams::{MiddlewareDataHttp}};
+use crate::{
+    mymiddleware::Logging,
+    appguts::{AppGuts, Lookup, MissPolicy, Protocol, State},
+    latency::Pacing,
+    ngrams::{MiddlewareDataHttp, MiddlewareDataRequest, ResponseTiming},
+    tee::Tee,
+    tls::TlsConfig,
+};
+
+/// UNKNOWN_EXCEPTION, ClickHouse has no dedicated code for "nothing recorded".
+pub const MISS_EXCEPTION_CODE: i32 = 1002;
 
-pub async fn start_http_handler(local_port: &str, remote_ip: &str, remote_port: &str, guts: AppGuts) -> io::Result<()> {
-    let forward_url = format!("http://{}:{}", &remote_ip, &remote_port);
+pub async fn start_http_handler(local_port: &str, remote_ip: &str, remote_port: &str, tls: Option<&TlsConfig>, guts: AppGuts) -> io::Result<()> {
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let forward_url = format!("{}://{}:{}", scheme, &remote_ip, &remote_port);
     let forward_url = Url::parse(&forward_url).unwrap();
 
     info!(
-        "Starting HTTP server at http://{}:{}",
+        "Starting {} server at {}://{}:{}",
+        scheme.to_uppercase(),
+        scheme,
         "0.0.0.0",
         &local_port,
     );
@@ -26,20 +38,32 @@ pub async fn start_http_handler(local_port: &str, remote_ip: &str, remote_port:
     info!("Forwarding to {forward_url}");
 
     let cpu_num = cmp::max(num_cpus::get() / 2, 1);
-
-    HttpServer::new(move || {
+    let connector = tls.map(TlsConfig::connector).transpose()?;
+
+    let server = HttpServer::new(move || {
+        let client = match &connector {
+            Some(connector) => Client::builder()
+                .connector(Connector::new().openssl(connector.clone()))
+                .finish(),
+            None => Client::default(),
+        };
         App::new()
-            .app_data(web::Data::new(Client::default()))
+            .app_data(web::Data::new(client))
             .app_data(web::Data::new(forward_url.clone()))
             .app_data(web::Data::new(guts.clone()))
             // .wrap(middleware::Logger::default())
             .wrap(Logging)
             .default_service(web::to(forward))
-    })
-    .bind(("0.0.0.0", local_port.parse::<u16>().unwrap()))?
-    .workers(cpu_num)
-    .run()
-    .await
+    });
+    let addr = ("0.0.0.0", local_port.parse::<u16>().unwrap());
+    let server = match tls {
+        Some(tls) => server.bind_openssl(addr, tls.acceptor()?)?,
+        None => server.bind(addr)?,
+    };
+    server
+        .workers(cpu_num)
+        .run()
+        .await
 }
 
 async fn forward(
@@ -61,91 +85,157 @@ async fn forward(
         None => forwarded_req,
     };
 
-    let mut req_body = web::BytesMut::new();
-    while let Some(chunk) = payload.next().await {
-        req_body.extend_from_slice(&chunk?);
-    }
-    let req_body = req_body.freeze();
-
-    let mut req_body_clone = web::BytesMut::with_capacity(req_body.len());
-    req_body_clone.resize(req_body.len(), 0);
-    req_body_clone.clone_from_slice(&req_body[..]);
-
-    let req_body_str = std::str::from_utf8(&req_body_clone[..]).unwrap_or("Invalid UTF-8 value").to_string();
-    debug!("body_str: {:?}", &req_body_str);
+    let state = guts.lock().unwrap().state(Protocol::Http);
 
-    let payload = req_body.slice(..);
-    let single_part: Result<web::Bytes, PayloadError> = Ok(payload);
-    let in_memory_stream = stream::once(future::ready(single_part));
-    let payload: Pin<Box<dyn Stream<Item=Result<web::Bytes, PayloadError>>>> = Box::pin(in_memory_stream);
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let req_query = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .collect::<Vec<(String, String)>>();
+    let req_headers = req.headers().clone();
+    let new_req_meta = move |body| MiddlewareDataRequest::new(method.clone(), path.clone(), req_query.clone(), &req_headers, body);
 
     ///////////////////////////
 
-    let is_record: bool;
-    {
-        let guts = guts.lock().unwrap();
-        is_record = guts.is_record_state();
-    }
-
-    ///////////////////////////
-
-    if is_record {
-
-        let mut resp = forwarded_req
-            .send_stream(payload)
-            .await
-            .map_err(error::ErrorInternalServerError)?;
-
-        let resp_status = resp.status().clone();
-        let resp_headers = resp.headers().clone();
-
-        let mut resp_body = web::BytesMut::new();
-        while let Some(chunk) = resp.next().await {
-            resp_body.extend_from_slice(&chunk?);
+    // Only matching needs the whole request body up front, otherwise it is streamed to ClickHouse
+    // and collected on the way for the recording.
+    let req_body = Rc::new(RefCell::new(None));
+    let payload: Pin<Box<dyn Stream<Item=Result<web::Bytes, PayloadError>>>> = if matches!(state, State::Replay | State::ReplayOrRecord) {
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
         }
-        let resp_body = resp_body.freeze();
+        let body = body.freeze();
+        let req_meta = new_req_meta(body.clone());
+        let req_query_str = req_meta.clickhouse_query();
+        debug!("query_str: {:?}", &req_query_str);
 
-        let mut resp_body_clone = web::BytesMut::with_capacity(resp_body.len());
-        resp_body_clone.resize(resp_body.len(), 0);
-        resp_body_clone.clone_from_slice(&resp_body[..]);
-        debug!("resp_body_clone: {:?}", &resp_body_clone);
+        let started = Instant::now();
+        let answer = {
+            let mut guts = guts.lock().unwrap();
+            match guts.find_best_answer(req_query_str.clone(), Some(req_meta)) {
+                Lookup::Hit(resp, http, pacing, score) => {
+                    info!("Replay hit for {:?} with {} score {:.2}", &req_query_str, guts.matcher().name(), score);
+                    Some((resp, http, pacing))
+                }
+                Lookup::Miss(score) if state == State::ReplayOrRecord => {
+                    info!("Replay miss for {:?} with best {} score {:?}, recording it", &req_query_str, guts.matcher().name(), score);
+                    None
+                }
+                Lookup::Miss(score) => {
+                    info!("Replay miss for {:?} with best {} score {:?}, answering with {:?}", &req_query_str, guts.matcher().name(), score, guts.miss_policy());
+                    return Ok(miss_response(guts.miss_policy()));
+                }
+            }
+        };
+
+        if let Some((resp, http, pacing)) = answer {
+            let (resp_status, resp_headers) = http.split();
+
+            if let Some(pacing) = pacing {
+                debug!("Replaying {:?} in {:?} instead of {:?}", &req_query_str, pacing.simulated, pacing.recorded);
+                time::sleep_until(time::Instant::from_std(started + pacing.simulated.first_byte)).await;
+            }
+
+            let mut client_resp = HttpResponse::build(resp_status);
+            for (header_name, header_value) in resp_headers.iter().filter(|(h, _)| *h != "connection") {
+                client_resp.insert_header((header_name.clone(), header_value.clone()));
+            }
+            let client_resp = client_resp.streaming(replay_chunks(resp, &http, started, pacing));
+
+            return Ok(client_resp);
+        }
 
-        let resp = resp_body.slice(..);
-        let single_part: Result<web::Bytes, PayloadError> = Ok(resp);
-        let in_memory_stream = stream::once(future::ready(single_part));
-        let resp: Pin<Box<dyn Stream<Item=Result<web::Bytes, PayloadError>>>> = Box::pin(in_memory_stream);
+        *req_body.borrow_mut() = Some(body.clone());
+        Box::pin(stream::once(future::ready(Ok(body))))
+    } else if state == State::Passthrough {
+        Box::pin(payload)
+    } else {
+        let req_body = req_body.clone();
+        Box::pin(Tee::new(payload, Instant::now(), move |body, _| *req_body.borrow_mut() = Some(body)))
+    };
 
-        let mut client_resp = HttpResponse::build(resp_status);
-        for (header_name, header_value) in resp_headers.iter().filter(|(h, _)| *h != "connection") {
-            client_resp.insert_header((header_name.clone(), header_value.clone()));
-        }
-        let client_resp = client_resp.streaming(resp);
+    ///////////////////////////
 
-        {
-            let mut guts = guts.lock().unwrap();
-            guts.insert_data(req_body_str, resp_body_clone.into(), Some(MiddlewareDataHttp::new(resp_status, resp_headers)));
-        }
+    let started = Instant::now();
+    let resp = forwarded_req
+        .send_stream(payload)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
 
-        return Ok(client_resp);
+    let resp_status = resp.status();
+    let resp_headers = resp.headers().clone();
 
-    } else {
+    let mut client_resp = HttpResponse::build(resp_status);
+    for (header_name, header_value) in resp_headers.iter().filter(|(h, _)| *h != "connection") {
+        client_resp.insert_header((header_name.clone(), header_value.clone()));
+    }
 
-        let guts = guts.lock().unwrap();
+    if state == State::Passthrough {
+        return Ok(client_resp.streaming(resp));
+    }
 
-        let (resp, status_headers) = guts.find_best_answer(req_body_str);
-        let (resp_status, resp_headers) = status_headers.split();
+    // The answer is recorded once upstream has sent all of it, unless the client is gone by then.
+    let guts = guts.get_ref().clone();
+    let http = MiddlewareDataHttp::new(resp_status, resp_headers);
+    let headers_at = started.elapsed();
+    let resp = Tee::new(resp, started, move |resp_body, chunks| {
+        let Some(req_body) = req_body.take() else {
+            warn!("ClickHouse answered before the whole request body was sent, not recording it");
+            return;
+        };
+        let req_meta = new_req_meta(req_body);
+        let req_query_str = req_meta.clickhouse_query();
+        debug!("query_str: {:?}, resp_body: {:?} in {} chunks", &req_query_str, &resp_body, chunks.len());
+        let timing = ResponseTiming {
+            first_byte: headers_at,
+            total: started.elapsed(),
+        };
+        let http = http.with_chunks(chunks);
+        let mut guts = guts.lock().unwrap();
+        guts.insert_data(req_query_str, Some(req_meta), resp_body, Some(http), Some(timing));
+    });
+    let client_resp = client_resp.streaming(resp);
+
+    Ok(client_resp)
+}
 
-        let single_part: Result<web::Bytes, PayloadError> = Ok(resp);
-        let in_memory_stream = stream::once(future::ready(single_part));
-        let resp: Pin<Box<dyn Stream<Item=Result<web::Bytes, PayloadError>>>> = Box::pin(in_memory_stream);
+/// Splits a recorded body into the chunks it was received in.
+/// With a pacing they are sent at their simulated times, otherwise all at once.
+fn replay_chunks(
+    mut body: web::Bytes,
+    http: &MiddlewareDataHttp,
+    started: Instant,
+    pacing: Option<Pacing>,
+) -> impl Stream<Item=Result<web::Bytes, PayloadError>> {
+    let mut chunks = Vec::new();
+    for chunk in http.chunks() {
+        chunks.push((chunk.at, body.split_to(chunk.len.min(body.len()))));
+    }
+    // Recordings without chunk timings are sent as one chunk at the end.
+    if !body.is_empty() || chunks.is_empty() {
+        let at = pacing.map_or(Duration::ZERO, |pacing| pacing.recorded.total);
+        chunks.push((at, body));
+    }
 
-        let mut client_resp = HttpResponse::build(resp_status);
-        for (header_name, header_value) in resp_headers.iter().filter(|(h, _)| *h != "connection") {
-            client_resp.insert_header((header_name.clone(), header_value.clone()));
+    stream::iter(chunks).then(move |(at, chunk)| async move {
+        if let Some(pacing) = pacing {
+            time::sleep_until(time::Instant::from_std(started + pacing.at(at))).await;
         }
-        let client_resp = client_resp.streaming(resp);
+        Ok(chunk)
+    })
+}
 
-        return Ok(client_resp);
+fn miss_response(policy: &MissPolicy) -> HttpResponse {
+    match policy {
+        MissPolicy::ClickHouseError => HttpResponse::InternalServerError()
+            .insert_header(("X-ClickHouse-Exception-Code", MISS_EXCEPTION_CODE.to_string()))
+            .content_type("text/plain; charset=UTF-8")
+            .body(format!(
+                "Code: {}. DB::Exception: No recording matches the query in network-replay-server. (UNKNOWN_EXCEPTION)\n",
+                MISS_EXCEPTION_CODE,
+            )),
+        MissPolicy::NotFound => HttpResponse::NotFound().body("No matching recording\n"),
+        MissPolicy::DefaultBody(body) => HttpResponse::Ok().body(body.clone()),
     }
-
 }
@@ -0,0 +1,228 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::ngrams::ResponseTiming;
+
+/// How replayed answers reproduce the recorded upstream response times.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    /// Factor for the recorded times.
+    pub scale: f64,
+    /// Times are scaled by a random factor within `1 ± jitter` on top of `scale`.
+    pub jitter: f64,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self { scale: 1.0, jitter: 0.0, min: None, max: None }
+    }
+}
+
+impl Latency {
+    /// Recorded times sped up by `speed`.
+    pub fn speed(speed: f64) -> Self {
+        Self { scale: 1.0 / speed, ..Self::default() }
+    }
+
+    /// Simulated times for an answer recorded with `recorded` ones, the same jitter applies to all of them.
+    pub fn pacing(&self, recorded: ResponseTiming) -> Pacing {
+        let factor = (self.scale * (1.0 + self.jitter * random_unit())).max(0.0);
+        let simulate = |time: Duration| {
+            let mut time = time.mul_f64(factor);
+            if let Some(min) = self.min {
+                time = time.max(min);
+            }
+            if let Some(max) = self.max {
+                time = time.min(max);
+            }
+            time
+        };
+        let total = simulate(recorded.total);
+        let simulated = ResponseTiming {
+            first_byte: simulate(recorded.first_byte).min(total),
+            total,
+        };
+        Pacing { recorded, simulated }
+    }
+}
+
+/// Comma-separated `scale=F`, `jitter=F`, `min=DURATION` and `max=DURATION`, like `scale=0.5,jitter=0.1,max=2s`.
+impl FromStr for Latency {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut latency = Latency::default();
+        for setting in spec.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or(format!("expected key=value, got {:?}", setting))?;
+            let number = || value.parse::<f64>().map_err(|e| format!("invalid {} {:?}, {}", key, value, e));
+            match key {
+                "scale" => latency.scale = number()?,
+                "jitter" => latency.jitter = number()?,
+                "min" => latency.min = Some(parse_duration(value)?),
+                "max" => latency.max = Some(parse_duration(value)?),
+                _ => return Err(format!("unknown latency setting {:?}", key)),
+            }
+        }
+        if !(latency.scale >= 0.0 && latency.scale.is_finite()) {
+            return Err(format!("scale must be a non-negative number, got {}", latency.scale));
+        }
+        if !(0.0..=1.0).contains(&latency.jitter) {
+            return Err(format!("jitter must be between 0 and 1, got {}", latency.jitter));
+        }
+        Ok(latency)
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "scale={},jitter={}", self.scale, self.jitter)?;
+        if let Some(min) = self.min {
+            write!(f, ",min={}ms", min.as_millis())?;
+        }
+        if let Some(max) = self.max {
+            write!(f, ",max={}ms", max.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Latency for replayed answers to queries containing `pattern`, case-insensitively.
+#[derive(Debug, Clone)]
+pub struct LatencyRule {
+    pub pattern: String,
+    pub latency: Latency,
+}
+
+impl LatencyRule {
+    pub fn new(pattern: &str, latency: Latency) -> Self {
+        Self { pattern: pattern.to_lowercase(), latency }
+    }
+
+    pub fn matches(&self, query: &str) -> bool {
+        query.to_lowercase().contains(&self.pattern)
+    }
+}
+
+/// Maps times of a recorded answer to the simulated ones.
+#[derive(Debug, Clone, Copy)]
+pub struct Pacing {
+    pub recorded: ResponseTiming,
+    pub simulated: ResponseTiming,
+}
+
+impl Pacing {
+    /// When to send what was received `recorded` after the request: the time to the first byte and
+    /// the rest of the answer are stretched separately to their simulated durations.
+    pub fn at(&self, recorded: Duration) -> Duration {
+        let (from, to) = (self.recorded, self.simulated);
+        let recorded = recorded.min(from.total);
+        if recorded <= from.first_byte {
+            stretch(recorded, from.first_byte, to.first_byte)
+        } else {
+            to.first_byte + stretch(recorded - from.first_byte, from.total - from.first_byte, to.total - to.first_byte)
+        }
+    }
+}
+
+/// `time` out of `from` as the same share of `to`.
+fn stretch(time: Duration, from: Duration, to: Duration) -> Duration {
+    if from.is_zero() {
+        to
+    } else {
+        to.mul_f64(time.as_secs_f64() / from.as_secs_f64())
+    }
+}
+
+/// Durations like `250ms`, `1.5s` or `800us`, plain numbers are milliseconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "ms"),
+    };
+    let number: f64 = number.parse().map_err(|e| format!("invalid duration {:?}, {}", value, e))?;
+    let seconds = match unit {
+        "us" => number / 1_000_000.0,
+        "ms" => number / 1000.0,
+        "s" => number,
+        _ => return Err(format!("unknown duration unit {:?}", unit)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration {:?}, {}", value, e))
+}
+
+/// Random number within [-1, 1], hashers of new `RandomState`s are randomly keyed.
+fn random_unit() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    random as f64 / u64::MAX as f64 * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn timing(first_byte: u64, total: u64) -> ResponseTiming {
+        ResponseTiming { first_byte: ms(first_byte), total: ms(total) }
+    }
+
+    #[test]
+    fn parse_spec() {
+        let latency: Latency = "scale=0.5, jitter=0.1,min=10ms,max=1.5s".parse().unwrap();
+        assert_eq!(latency, Latency { scale: 0.5, jitter: 0.1, min: Some(ms(10)), max: Some(ms(1500)) });
+        assert_eq!(latency.to_string(), "scale=0.5,jitter=0.1,min=10ms,max=1500ms");
+        assert_eq!("".parse::<Latency>().unwrap(), Latency::default());
+        assert_eq!("min=800us".parse::<Latency>().unwrap().min, Some(Duration::from_micros(800)));
+        assert_eq!("max=20".parse::<Latency>().unwrap().max, Some(ms(20)));
+        for bad in ["scale", "scale=fast", "scale=-1", "jitter=2", "speed=1", "min=1h", "max=-5ms"] {
+            assert!(bad.parse::<Latency>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn pacing_scales_and_clamps() {
+        assert_eq!(Latency::speed(2.0).pacing(timing(10, 40)).simulated, timing(5, 20));
+
+        let clamped = Latency { min: Some(ms(30)), max: Some(ms(50)), ..Latency::default() };
+        assert_eq!(clamped.pacing(timing(10, 100)).simulated, timing(30, 50));
+        // The first byte never comes after the end.
+        let clamped = Latency { max: Some(ms(20)), ..clamped };
+        assert_eq!(clamped.pacing(timing(10, 100)).simulated, timing(20, 20));
+
+        let jittery = Latency { jitter: 0.5, ..Latency::default() };
+        for _ in 0..20 {
+            let simulated = jittery.pacing(timing(10, 100)).simulated;
+            assert!(simulated.total >= ms(50) && simulated.total <= ms(150), "{:?}", simulated);
+        }
+    }
+
+    #[test]
+    fn pacing_stretches_both_phases() {
+        let pacing = Pacing { recorded: timing(10, 110), simulated: timing(40, 90) };
+        assert_eq!(pacing.at(ms(0)), ms(0));
+        assert_eq!(pacing.at(ms(5)), ms(20));
+        assert_eq!(pacing.at(ms(10)), ms(40));
+        assert_eq!(pacing.at(ms(60)), ms(65));
+        assert_eq!(pacing.at(ms(110)), ms(90));
+        assert_eq!(pacing.at(ms(500)), ms(90));
+
+        // Answers that arrived at once are sent at the simulated time to the first byte.
+        let pacing = Pacing { recorded: timing(0, 0), simulated: timing(30, 30) };
+        assert_eq!(pacing.at(ms(0)), ms(30));
+    }
+
+    #[test]
+    fn rules_match_case_insensitively() {
+        let rule = LatencyRule::new("System.Parts", Latency::default());
+        assert!(rule.matches("select * from SYSTEM.PARTS where active"));
+        assert!(!rule.matches("select * from system.tables"));
+    }
+}
@@ -0,0 +1,336 @@
+use regex::Regex;
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    columns,
+    native::{self, CompressionMethod, ProtocolState},
+    ngrams::MiddlewareData,
+};
+
+/// Entries per page if no `limit` is given.
+pub const DEFAULT_LIMIT: usize = 50;
+/// Query characters shown in the summary table if no `width` is given.
+pub const DEFAULT_WIDTH: usize = 60;
+
+pub enum QueryFilter {
+    /// Case-insensitive substring.
+    Contains(String),
+    Regex(Regex),
+}
+
+impl QueryFilter {
+    fn matches(&self, query: &str) -> bool {
+        match self {
+            QueryFilter::Contains(pattern) => query.to_lowercase().contains(pattern),
+            QueryFilter::Regex(regex) => regex.is_match(query),
+        }
+    }
+}
+
+/// Entries to list, every set criterion must match.
+#[derive(Default)]
+pub struct EntryFilter {
+    pub query: Option<QueryFilter>,
+    /// HTTP status, native entries have none.
+    pub status: Option<u16>,
+    /// Recorded at or after, entries without a recording time never match a time range.
+    pub from: Option<SystemTime>,
+    /// Recorded before.
+    pub to: Option<SystemTime>,
+}
+
+impl EntryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.query.is_none() && self.status.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    pub fn matches(&self, data: &MiddlewareData) -> bool {
+        if let Some(query) = &self.query {
+            if !query.matches(data.request()) {
+                return false;
+            }
+        }
+        if let Some(status) = self.status {
+            if data.http().map(|http| http.split().0.as_u16()) != Some(status) {
+                return false;
+            }
+        }
+        if self.from.is_some() || self.to.is_some() {
+            let Some(at) = data.recorded_at() else { return false };
+            if self.from.is_some_and(|from| at < from) || self.to.is_some_and(|to| at >= to) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Filter and page of a listing request.
+pub struct Listing {
+    pub filter: EntryFilter,
+    pub offset: usize,
+    pub limit: usize,
+    pub width: usize,
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Self { filter: EntryFilter::default(), offset: 0, limit: DEFAULT_LIMIT, width: DEFAULT_WIDTH }
+    }
+}
+
+impl Listing {
+    /// Options are `contains`, `regex`, `status`, `from`, `to` (Unix seconds or RFC 3339), `offset`, `limit` and `width`.
+    pub fn from_options<'a>(options: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self, String> {
+        let mut listing = Listing::default();
+        for (key, value) in options {
+            let number = || value.parse::<usize>().map_err(|e| format!("invalid {} {:?}, {}", key, value, e));
+            match key {
+                "contains" => listing.filter.query = Some(QueryFilter::Contains(value.to_lowercase())),
+                "regex" => {
+                    let regex = Regex::new(value).map_err(|e| format!("invalid regex {:?}, {}", value, e))?;
+                    listing.filter.query = Some(QueryFilter::Regex(regex));
+                }
+                "status" => listing.filter.status = Some(value.parse().map_err(|e| format!("invalid status {:?}, {}", value, e))?),
+                "from" => listing.filter.from = Some(parse_time(value)?),
+                "to" => listing.filter.to = Some(parse_time(value)?),
+                "offset" => listing.offset = number()?,
+                "limit" => listing.limit = number()?,
+                "width" => listing.width = number()?,
+                _ => return Err(format!("unknown option {:?}", key)),
+            }
+        }
+        Ok(listing)
+    }
+
+    /// Matching entries of the page with their Db indexes, and the number of all matching entries.
+    pub fn page<'a>(&self, entries: impl Iterator<Item = &'a MiddlewareData>) -> (Vec<(usize, &'a MiddlewareData)>, usize) {
+        let mut page = Vec::new();
+        let mut total = 0;
+        for (idx, data) in entries.enumerate().filter(|(_, data)| self.filter.matches(data)) {
+            if total >= self.offset && page.len() < self.limit {
+                page.push((idx, data));
+            }
+            total += 1;
+        }
+        (page, total)
+    }
+}
+
+/// Splits `key=value key="value with spaces"` options of a text command.
+pub fn split_options(args: &str) -> Result<Vec<(String, String)>, String> {
+    let mut options = Vec::new();
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=').ok_or(format!("expected key=value, got {:?}", rest))?;
+        if key.contains(char::is_whitespace) {
+            return Err(format!("expected key=value, got {:?}", key));
+        }
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').ok_or(format!("unterminated quote in {:?}", rest))?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => after.split_at(after.find(char::is_whitespace).unwrap_or(after.len())),
+        };
+        options.push((key.to_string(), value.to_string()));
+        rest = after.trim_start();
+    }
+    Ok(options)
+}
+
+/// Unix timestamp in seconds or RFC 3339 time like `2024-05-01T12:00:00Z`.
+fn parse_time(value: &str) -> Result<SystemTime, String> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds)
+            .map(|since_epoch| UNIX_EPOCH + since_epoch)
+            .map_err(|e| format!("invalid time {:?}, {}", value, e));
+    }
+    humantime::parse_rfc3339_weak(value).map_err(|e| format!("invalid time {:?}, {}", value, e))
+}
+
+pub fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// One line per entry with index, method, path, status, response size and the beginning of the query.
+pub fn table(entries: &[(usize, &MiddlewareData)], width: usize) -> String {
+    let mut out = format!("{:>6} {:<7} {:<24} {:>6} {:>10}  query\n", "index", "method", "path", "status", "size");
+    for (idx, data) in entries {
+        let meta = data.request_meta();
+        let status = data.http().map_or("-".to_string(), |http| http.split().0.as_u16().to_string());
+        writeln!(
+            out,
+            "{:>6} {:<7} {:<24} {:>6} {:>10}  {}",
+            idx,
+            meta.map_or("-", |meta| meta.method().as_str()),
+            meta.map_or("-", |meta| meta.path()),
+            status,
+            data.response().len(),
+            query_prefix(data.request(), width),
+        ).unwrap();
+    }
+    out
+}
+
+/// First `width` characters of the query on a single line.
+fn query_prefix(query: &str, width: usize) -> String {
+    let mut prefix = query.chars()
+        .take(width)
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .collect::<String>();
+    if query.chars().nth(width).is_some() {
+        prefix.push_str("...");
+    }
+    prefix
+}
+
+/// Everything recorded for the entry: request, response parameters, timings and the response itself.
+pub fn describe(idx: usize, data: &MiddlewareData) -> String {
+    let mut out = format!("Entry {}\n", idx);
+    if let Some(at) = data.recorded_at() {
+        writeln!(out, "Recorded at: {}", format_time(at)).unwrap();
+    }
+    if let Some(meta) = data.request_meta() {
+        writeln!(out, "Request: {} {}", meta.method(), meta.path()).unwrap();
+        for (key, value) in meta.query().iter().filter(|(key, _)| key != "query") {
+            writeln!(out, "  param {}={}", key, value).unwrap();
+        }
+        for (name, value) in meta.headers() {
+            writeln!(out, "  {}: {}", name, String::from_utf8_lossy(value.as_bytes())).unwrap();
+        }
+    }
+    if let Some(native) = data.native() {
+        writeln!(out, "Native: revision {}, compression {}", native.revision, native.compression).unwrap();
+    }
+    writeln!(out, "Query:\n{}", data.request()).unwrap();
+
+    if let Some(http) = data.http() {
+        let (status, headers) = http.split();
+        writeln!(out, "Status: {}", status).unwrap();
+        for (name, value) in headers.iter() {
+            writeln!(out, "  {}: {}", name, String::from_utf8_lossy(value.as_bytes())).unwrap();
+        }
+        if !http.chunks().is_empty() {
+            writeln!(out, "Chunks: {}", http.chunks().len()).unwrap();
+        }
+    }
+    if let Some(timing) = data.timing() {
+        writeln!(out, "Timing: first byte {:?}, total {:?}", timing.first_byte, timing.total).unwrap();
+    }
+    writeln!(out, "Response ({} bytes):\n{}", data.response().len(), response_text(data)).unwrap();
+    out
+}
+
+/// Native answers are rendered packet by packet with their blocks as tables, HTTP bodies as text.
+pub fn response_text(data: &MiddlewareData) -> String {
+    match data.native() {
+        Some(native) => {
+            let state = ProtocolState {
+                revision: native.revision,
+                compression: native.compression.then_some(CompressionMethod::Lz4),
+            };
+            native::render_server_packets(data.response(), &state)
+        }
+        None => body_text(data.response()),
+    }
+}
+
+/// UTF-8 bodies as they are, anything else as hex.
+pub fn body_text(body: &[u8]) -> String {
+    match std::str::from_utf8(body) {
+        Ok(text) => text.to_string(),
+        Err(_) => columns::hex(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ngrams::MiddlewareDataHttp;
+    use actix_web::http::{header::HeaderMap, StatusCode};
+    use bytes::Bytes;
+
+    fn entry(query: &str, status: StatusCode, recorded_at: Option<u64>) -> MiddlewareData {
+        let http = MiddlewareDataHttp::new(status, HeaderMap::new());
+        MiddlewareData::new(query.to_string(), None, Bytes::new(), Some(http))
+            .with_recorded_at(recorded_at.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+
+    fn listing(options: &[(&str, &str)]) -> Listing {
+        Listing::from_options(options.iter().copied()).unwrap()
+    }
+
+    fn indexes(listing: &Listing, entries: &[MiddlewareData]) -> (Vec<usize>, usize) {
+        let (page, total) = listing.page(entries.iter());
+        (page.into_iter().map(|(idx, _)| idx).collect(), total)
+    }
+
+    #[test]
+    fn filters() {
+        let entries = [
+            entry("SELECT * FROM events", StatusCode::OK, Some(100)),
+            entry("select * from users", StatusCode::NOT_FOUND, Some(200)),
+            entry("select 1", StatusCode::OK, None),
+        ];
+        assert_eq!(indexes(&listing(&[("contains", "from EVENTS")]), &entries).0, [0]);
+        assert_eq!(indexes(&listing(&[("regex", "^select")]), &entries).0, [1, 2]);
+        assert_eq!(indexes(&listing(&[("status", "200")]), &entries).0, [0, 2]);
+        // Entries without a recording time never match a time range, `to` is exclusive.
+        assert_eq!(indexes(&listing(&[("from", "100")]), &entries).0, [0, 1]);
+        assert_eq!(indexes(&listing(&[("to", "200")]), &entries).0, [0]);
+        assert_eq!(indexes(&listing(&[("from", "1970-01-01T00:01:40Z"), ("status", "404")]), &entries).0, [1]);
+
+        let native = MiddlewareData::new_native("select 1".to_string(), Bytes::new(), crate::ngrams::MiddlewareDataNative { revision: 54453, compression: false });
+        assert!(!listing(&[("status", "200")]).filter.matches(&native));
+        assert!(EntryFilter::default().matches(&native));
+    }
+
+    #[test]
+    fn pages() {
+        let entries = (0..7).map(|i| entry(&format!("select {}", i), StatusCode::OK, None)).collect::<Vec<_>>();
+        assert_eq!(indexes(&listing(&[]), &entries), (vec![0, 1, 2, 3, 4, 5, 6], 7));
+        assert_eq!(indexes(&listing(&[("offset", "2"), ("limit", "3")]), &entries), (vec![2, 3, 4], 7));
+        assert_eq!(indexes(&listing(&[("offset", "10")]), &entries), (vec![], 7));
+        // Offsets count matching entries, indexes stay those of the Db.
+        assert_eq!(indexes(&listing(&[("regex", "[135]$"), ("offset", "1")]), &entries), (vec![3, 5], 3));
+    }
+
+    #[test]
+    fn bad_options() {
+        for options in [&[("limit", "-1")][..], &[("regex", "(")], &[("status", "ok")], &[("from", "yesterday")], &[("sort", "asc")]] {
+            assert!(Listing::from_options(options.iter().copied()).is_err(), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn options_of_text_commands() {
+        let options = split_options(r#" contains="from system.tables"  limit=5 regex=a=b"#).unwrap();
+        assert_eq!(options, [
+            ("contains".to_string(), "from system.tables".to_string()),
+            ("limit".to_string(), "5".to_string()),
+            ("regex".to_string(), "a=b".to_string()),
+        ]);
+        assert!(split_options("").unwrap().is_empty());
+        assert!(split_options("limit").is_err());
+        assert!(split_options("two words=1").is_err());
+        assert!(split_options(r#"contains="open"#).is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1714564800.5").unwrap(), UNIX_EPOCH + Duration::from_millis(1_714_564_800_500));
+        assert_eq!(parse_time("2024-05-01T12:00:00Z").unwrap(), UNIX_EPOCH + Duration::from_secs(1_714_564_800));
+        assert!(parse_time("-1").is_err());
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_millis(1_714_564_800_500)), "2024-05-01T12:00:00.500Z");
+    }
+
+    #[test]
+    fn long_queries_are_cut() {
+        assert_eq!(query_prefix("select\n  1", 20), "select   1");
+        assert_eq!(query_prefix("select 1", 6), "select...");
+    }
+}
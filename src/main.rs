@@ -1,38 +1,192 @@
-use log::debug;
-use std::sync::{Arc, Mutex};
+use clap::ArgMatches;
+use log::{debug, error, info};
+use std::{future::Future, path::PathBuf, str::FromStr, sync::{Arc, Mutex}};
 use tokio::{
     io,
     select,
 };
 
-use crate::appguts::{AppGuts, UnsafeAppGuts};
+use crate::{
+    appguts::{AppGuts, MissPolicy, ReplayConfig, UnsafeAppGuts},
+    latency::{Latency, LatencyRule},
+    matcher::MatcherConfig,
+    native::ServerHello,
+    tls::{Identity, TlsConfig},
+};
 
+mod admin;
+mod cassette;
 mod cli;
+mod columns;
 mod control;
+mod edit;
 mod http;
+mod latency;
+mod listing;
+mod matcher;
+mod native;
+mod sql;
 mod tcp;
+mod tee;
+mod tls;
+#[cfg(test)]
+mod testutil;
 pub mod appguts;
 pub mod mymiddleware;
 pub mod ngrams;
 
+/// Options that turn the secure ports on.
+const TLS_ARGS: [&str; 11] = [
+    "tls_cert", "tls_key", "tls_san", "tls_ca", "persist_ca", "upstream_ca", "insecure_upstream",
+    "https_port_local", "tcp_port_secure_local", "https_port_clickhouse", "tcp_port_secure_clickhouse",
+];
+
+/// Runs the handler `start` makes with `tls`, or never finishes without it.
+async fn serve_if<'a, F: Future<Output = io::Result<()>>>(tls: Option<&'a TlsConfig>, start: impl FnOnce(&'a TlsConfig) -> F) -> io::Result<()> {
+    match tls {
+        Some(tls) => start(tls).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} must be a number, not {:?}", name, value))
+}
+
+/// Replay settings from the command line and the matcher config, or what is wrong with them.
+fn replay_config(args: &ArgMatches) -> Result<ReplayConfig, String> {
+    let miss_policy = match args.value_of("miss_policy").unwrap().parse()? {
+        MissPolicy::DefaultBody(_) => MissPolicy::DefaultBody(args.value_of("miss_body").unwrap().to_string().into()),
+        policy => policy,
+    };
+    let matcher_config = match args.value_of("matcher_config") {
+        Some(path) => MatcherConfig::load(path).map_err(|e| format!("invalid matcher config {:?}: {}", path, e))?,
+        None => MatcherConfig::default(),
+    };
+    // Options given on the command line take precedence over the config file, their defaults don't.
+    let given = |name| args.value_of(name).filter(|_| args.occurrences_of(name) > 0);
+    let matcher_name = given("matcher").map(str::to_string)
+        .or(matcher_config.matcher)
+        .unwrap_or_else(|| args.value_of("matcher").unwrap().to_string());
+    let ngram_size = match given("ngram_size") {
+        Some(n) => number("ngram_size", n)?,
+        None => match matcher_config.ngram_size {
+            Some(n) => n,
+            None => number("ngram_size", args.value_of("ngram_size").unwrap())?,
+        },
+    };
+    let latency = match (args.value_of("latency"), args.value_of("replay_speed")) {
+        (Some(spec), _) => Some(spec.parse()?),
+        (None, Some(speed)) => {
+            let speed: f64 = number("replay_speed", speed)?;
+            if !(speed > 0.0 && speed.is_finite()) {
+                return Err(format!("replay_speed must be positive, not {}", speed));
+            }
+            Some(Latency::speed(speed))
+        }
+        (None, None) => None,
+    };
+    let latency_rules = args.values_of("latency_rule").into_iter().flatten()
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .map(|rule| rule[1].parse().map(|latency| LatencyRule::new(rule[0], latency)).map_err(|e| format!("invalid latency rule {:?}: {}", rule[0], e)))
+        .collect::<Result<_, _>>()?;
+    Ok(ReplayConfig {
+        min_score: number("min_score", args.value_of("min_score").unwrap())?,
+        miss_policy,
+        mask_literals: args.is_present("mask_literals"),
+        matcher: matcher::from_name(&matcher_name, ngram_size)?,
+        ngram_size,
+        sequential: args.value_of("sequential").map(str::parse).transpose()?,
+        server_hello: args.value_of("server_version").map(ServerHello::with_version).transpose()?,
+        latency,
+        latency_rules,
+    })
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let args = cli::get_cli_args();
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
 
-    let guts: AppGuts = Arc::new(Mutex::new(UnsafeAppGuts::new()));
+    let replay = match replay_config(&args) {
+        Ok(replay) => replay,
+        Err(e) => {
+            error!("{}", e);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+    };
+
+    if let Some(latency) = replay.latency {
+        info!("Replaying with latency {}", latency);
+    }
+    for rule in replay.latency_rules.iter() {
+        info!("Replaying queries containing {:?} with latency {}", rule.pattern, rule.latency);
+    }
+
+    let guts: AppGuts = Arc::new(Mutex::new(UnsafeAppGuts::new(replay)));
     
     let tcp_port_local = args.value_of("tcp_port_local").unwrap();
     let tcp_port_remote= args.value_of("tcp_port_clickhouse").unwrap();
     let http_port_local = args.value_of("http_port_local").unwrap();
     let http_port_remote= args.value_of("http_port_clickhouse").unwrap();
+    let tcp_port_secure_local = args.value_of("tcp_port_secure_local").unwrap();
+    let tcp_port_secure_remote = args.value_of("tcp_port_secure_clickhouse").unwrap();
+    let https_port_local = args.value_of("https_port_local").unwrap();
+    let https_port_remote = args.value_of("https_port_clickhouse").unwrap();
     let udp_control_port = args.value_of("udp_control_port").unwrap();
+    let admin_port = args.value_of("admin_port").unwrap();
     let remote_ip = args.value_of("server").unwrap();
+    let cassette = args.value_of("cassette");
+    // The secure ports are only served when something about TLS was asked for.
+    let tls = if TLS_ARGS.iter().any(|name| args.occurrences_of(name) > 0) {
+        let identity = match (args.value_of("tls_cert"), args.value_of("tls_key")) {
+            (Some(cert), Some(key)) => Identity::Files { cert: cert.to_string(), key: key.to_string() },
+            _ => {
+                let mut sans: Vec<String> = tls::DEFAULT_SANS.iter().map(|san| san.to_string()).collect();
+                sans.extend(args.values_of("tls_san").into_iter().flatten().map(str::to_string));
+                let ca_path = match args.value_of("tls_ca") {
+                    Some(path) => Some(PathBuf::from(path)),
+                    None => tls::default_ca_path()?,
+                };
+                Identity::generate(&sans, ca_path.as_deref(), args.is_present("persist_ca"))?
+            }
+        };
+        let tls = TlsConfig {
+            identity,
+            upstream_ca: args.value_of("upstream_ca").map(str::to_string),
+            verify_upstream: !args.is_present("insecure_upstream"),
+        };
+        tls.acceptor()?;
+        tls.connector()?;
+        Some(tls)
+    } else {
+        info!("Not serving the secure ports, pass a --tls_* option or a secure port to serve them");
+        None
+    };
+
+    if let Some(path) = cassette {
+        if std::path::Path::new(path).exists() {
+            // Starting with an empty Db would overwrite the cassette with it on stop.
+            match guts.lock().unwrap().load_cassette(path) {
+                Ok(len) => info!("Loaded {} entries from cassette {:?}", len, path),
+                Err(e) => {
+                    error!("Failed to load cassette {:?}: {}", path, e);
+                    return Err(io::Error::new(e.kind(), format!("failed to load cassette {:?}: {}", path, e)));
+                }
+            }
+        } else {
+            info!("Cassette {:?} does not exist yet, starting with empty Db", path);
+        }
+    }
     
-    let http_handler = http::start_http_handler(&http_port_local, &remote_ip, &http_port_remote, guts.clone());
-    let tcp_handler = tcp::start_tcp_handler(&tcp_port_local, &remote_ip, &tcp_port_remote);
-    let udp_handler = control::start_udp_handler(&udp_control_port, guts.clone());
+    let http_handler = http::start_http_handler(http_port_local, remote_ip, http_port_remote, None, guts.clone());
+    let tcp_handler = tcp::start_tcp_handler(tcp_port_local, remote_ip, tcp_port_remote, None, guts.clone());
+    let https_handler = serve_if(tls.as_ref(), |tls| http::start_http_handler(https_port_local, remote_ip, https_port_remote, Some(tls), guts.clone()));
+    let tcp_secure_handler = serve_if(tls.as_ref(), |tls| tcp::start_tcp_handler(tcp_port_secure_local, remote_ip, tcp_port_secure_remote, Some(tls), guts.clone()));
+    let udp_handler = control::start_udp_handler(udp_control_port, guts.clone());
+    let admin_handler = admin::start_admin_handler(admin_port, guts.clone());
 
     select!(
         Ok(()) = http_handler => {
@@ -41,11 +195,28 @@ async fn main() -> io::Result<()> {
         Ok(()) = tcp_handler => {
             debug!("Tcp listener shut down")
         }
+        Ok(()) = https_handler => {
+            debug!("Https server shut down");
+        }
+        Ok(()) = tcp_secure_handler => {
+            debug!("Secure tcp listener shut down")
+        }
         Ok(()) = udp_handler => {
             debug!("App was stopped")
         }
+        Ok(()) = admin_handler => {
+            debug!("App was stopped through the admin API")
+        }
     );
 
+    if let Some(path) = cassette {
+        let guts = guts.lock().unwrap();
+        match guts.save_cassette(path) {
+            Ok(len) => info!("Saved {} entries to cassette {:?}, {} of them new", len, path, guts.new_recordings()),
+            Err(e) => error!("Failed to save cassette {:?}: {}", path, e),
+        }
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,259 @@
+use std::{fmt::Debug, fs, path::Path, sync::Arc};
+
+use crate::ngrams::{MiddlewareData, Ngrams};
+
+pub const MATCHERS: [&str; 6] = ["exact", "normalized", "ngrams", "jaccard", "cosine", "levenshtein"];
+
+/// Matching options of a JSON config file like `{"matcher": "jaccard", "ngram_size": 4}`, each of them optional.
+#[derive(Debug, Default)]
+pub struct MatcherConfig {
+    pub matcher: Option<String>,
+    pub ngram_size: Option<usize>,
+}
+
+impl MatcherConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let value = serde_json::from_str::<serde_json::Value>(text).map_err(|e| e.to_string())?;
+        let fields = value.as_object().ok_or("expected a JSON object")?;
+        let mut config = MatcherConfig::default();
+        for (key, value) in fields {
+            match key.as_str() {
+                "matcher" => {
+                    let name = value.as_str().ok_or("matcher must be a string")?;
+                    if !MATCHERS.contains(&name) {
+                        return Err(format!("unknown matcher: {}", name));
+                    }
+                    config.matcher = Some(name.to_string());
+                }
+                "ngram_size" => {
+                    let n = value.as_u64().filter(|n| *n > 0).ok_or("ngram_size must be a positive number")?;
+                    config.ngram_size = Some(n as usize);
+                }
+                _ => return Err(format!("unknown option {:?}", key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Strategy scoring how well a recording matches a request.
+/// With `mask_literals` strategies compare query fingerprints instead of normalized queries.
+pub trait Matcher: Debug + Send + Sync {
+    fn name(&self) -> String;
+
+    /// Score in `[0, 1]`, zero means the recording does not match at all.
+    fn score(&self, req: &MiddlewareData, data: &MiddlewareData, mask_literals: bool) -> f64;
+
+    /// Only recordings sharing at least one of the indexed 3-grams with the request can score above zero.
+    fn uses_ngrams_index(&self) -> bool {
+        false
+    }
+}
+
+/// Builds a matcher by its CLI name, `n` is used by the n-gram based ones.
+pub fn from_name(name: &str, n: usize) -> Result<Arc<dyn Matcher>, String> {
+    match name {
+        "exact" => Ok(Arc::new(ExactMatcher)),
+        "normalized" => Ok(Arc::new(NormalizedExactMatcher)),
+        "ngrams" => Ok(Arc::new(NgramsMatcher::new(n, Similarity::Intersection))),
+        "jaccard" => Ok(Arc::new(NgramsMatcher::new(n, Similarity::Jaccard))),
+        "cosine" => Ok(Arc::new(NgramsMatcher::new(n, Similarity::Cosine))),
+        "levenshtein" => Ok(Arc::new(LevenshteinMatcher)),
+        _ => Err(format!("unknown matcher: {}", name)),
+    }
+}
+
+/// Byte-for-byte equal query text.
+#[derive(Debug)]
+pub struct ExactMatcher;
+
+impl Matcher for ExactMatcher {
+    fn name(&self) -> String {
+        "exact".to_string()
+    }
+
+    fn score(&self, req: &MiddlewareData, data: &MiddlewareData, _mask_literals: bool) -> f64 {
+        if req.request() == data.request() { 1.0 } else { 0.0 }
+    }
+}
+
+/// Equal token sequences after SQL normalization.
+#[derive(Debug)]
+pub struct NormalizedExactMatcher;
+
+impl Matcher for NormalizedExactMatcher {
+    fn name(&self) -> String {
+        "normalized".to_string()
+    }
+
+    fn score(&self, req: &MiddlewareData, data: &MiddlewareData, mask_literals: bool) -> f64 {
+        if req.tokens(mask_literals) == data.tokens(mask_literals) { 1.0 } else { 0.0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Similarity {
+    /// Shared n-grams divided by the number of request n-grams.
+    Intersection,
+    Jaccard,
+    Cosine,
+}
+
+#[derive(Debug)]
+pub struct NgramsMatcher {
+    n: usize,
+    similarity: Similarity,
+}
+
+impl NgramsMatcher {
+    pub fn new(n: usize, similarity: Similarity) -> Self {
+        Self { n: n.max(1), similarity }
+    }
+}
+
+impl Default for NgramsMatcher {
+    fn default() -> Self {
+        Self::new(3, Similarity::Intersection)
+    }
+}
+
+impl Matcher for NgramsMatcher {
+    fn name(&self) -> String {
+        let name = match self.similarity {
+            Similarity::Intersection => "ngrams",
+            Similarity::Jaccard => "jaccard",
+            Similarity::Cosine => "cosine",
+        };
+        format!("{}(n={})", name, self.n)
+    }
+
+    fn score(&self, req: &MiddlewareData, data: &MiddlewareData, mask_literals: bool) -> f64 {
+        // Recordings keep 3-grams only, other sizes are built on the fly.
+        let built;
+        let (req_ngrams, data_ngrams) = if self.n == 3 {
+            (req.ngrams(mask_literals), data.ngrams(mask_literals))
+        } else {
+            built = (
+                Ngrams::new(self.n, req.tokens(mask_literals).to_vec()),
+                Ngrams::new(self.n, data.tokens(mask_literals).to_vec()),
+            );
+            (&built.0, &built.1)
+        };
+
+        let shared = req_ngrams.compatibility_score(data_ngrams) as f64;
+        let (req_len, data_len) = (req_ngrams.len() as f64, data_ngrams.len() as f64);
+        let denominator = match self.similarity {
+            Similarity::Intersection => req_len,
+            Similarity::Jaccard => req_len + data_len - shared,
+            Similarity::Cosine => (req_len * data_len).sqrt(),
+        };
+        if denominator > 0.0 { shared / denominator } else { 0.0 }
+    }
+
+    fn uses_ngrams_index(&self) -> bool {
+        self.n == 3
+    }
+}
+
+/// Token-level edit distance, normalized by the length of the longer query.
+#[derive(Debug)]
+pub struct LevenshteinMatcher;
+
+impl Matcher for LevenshteinMatcher {
+    fn name(&self) -> String {
+        "levenshtein".to_string()
+    }
+
+    fn score(&self, req: &MiddlewareData, data: &MiddlewareData, mask_literals: bool) -> f64 {
+        let (a, b) = (req.tokens(mask_literals), data.tokens(mask_literals));
+        let longest = a.len().max(b.len());
+        if longest == 0 {
+            return 1.0;
+        }
+        1.0 - levenshtein(a, b) as f64 / longest as f64
+    }
+}
+
+fn levenshtein(a: &[String], b: &[String]) -> usize {
+    let mut prev = (0..=b.len()).collect::<Vec<usize>>();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(x != y);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn query(text: &str) -> MiddlewareData {
+        MiddlewareData::new(text.to_string(), None, Bytes::new(), None)
+    }
+
+    fn score(name: &str, req: &str, data: &str) -> f64 {
+        from_name(name, 3).unwrap().score(&query(req), &query(data), false)
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(score("exact", "select 1", "select 1"), 1.0);
+        assert_eq!(score("exact", "select 1", "SELECT 1"), 0.0);
+        assert_eq!(score("normalized", "select 1", "SELECT  1 -- one"), 1.0);
+        assert_eq!(score("normalized", "select 1", "select 2"), 0.0);
+
+        // 6 request 3-grams, 3 of them shared with the 4 of the recording.
+        let (req, data) = ("select a, b from t where x", "select a, b from u");
+        assert_eq!(score("ngrams", req, data), 3.0 / 6.0);
+        assert_eq!(score("jaccard", req, data), 3.0 / 7.0);
+        assert_eq!(score("cosine", req, data), 3.0 / 24f64.sqrt());
+        // One of the eight tokens replaced and two missing.
+        assert_eq!(score("levenshtein", req, data), 1.0 - 3.0 / 8.0);
+    }
+
+    #[test]
+    fn names() {
+        for name in MATCHERS {
+            assert!(from_name(name, 2).unwrap().name().starts_with(name));
+        }
+        assert_eq!(from_name("jaccard", 2).unwrap().name(), "jaccard(n=2)");
+        assert!(from_name("fuzzy", 3).is_err());
+        assert_eq!(score("ngrams", "select a from t", "select a from t"), 1.0);
+    }
+
+    #[test]
+    fn masked_literals() {
+        let (req, data) = (query("select * from t where id = 1"), query("select * from t where id = 2"));
+        for name in MATCHERS {
+            let matcher = from_name(name, 3).unwrap();
+            if name != "exact" {
+                assert_eq!(matcher.score(&req, &data, true), 1.0, "{}", name);
+            }
+            assert!(matcher.score(&req, &data, false) < 1.0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn matcher_config() {
+        let config = MatcherConfig::parse(r#"{"matcher": "jaccard", "ngram_size": 4}"#).unwrap();
+        assert_eq!((config.matcher.as_deref(), config.ngram_size), (Some("jaccard"), Some(4)));
+        let config = MatcherConfig::parse("{}").unwrap();
+        assert_eq!((config.matcher, config.ngram_size), (None, None));
+
+        assert!(MatcherConfig::parse(r#"{"matcher": "fuzzy"}"#).is_err());
+        assert!(MatcherConfig::parse(r#"{"ngram_size": 0}"#).is_err());
+        assert!(MatcherConfig::parse(r#"{"min_score": 0.5}"#).is_err());
+        assert!(MatcherConfig::parse("[]").is_err());
+    }
+}
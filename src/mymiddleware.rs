@@ -1,20 +1,21 @@
 use actix_web::{
-    body::{self, EitherBody, MessageBody},
-    dev::{self, Service, ServiceRequest, ServiceResponse, Transform, Payload},
-    web::{Bytes, BytesMut},
+    body::{BodySize, MessageBody},
+    dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
+    web::Bytes,
     error::PayloadError,
     Error,
     HttpMessage,
-    HttpResponseBuilder
 };
 use std::{
-    future::{self, ready, Ready},
+    future::{ready, Ready},
     pin::Pin,
     rc::Rc,
+    task::{Context, Poll},
 };
 use futures::Stream;
-use futures_util::{future::LocalBoxFuture, stream::{self, StreamExt}};
+use futures_util::{future::LocalBoxFuture, stream::StreamExt};
 use log::{debug};
+use pin_project::pin_project;
 
 pub struct Logging;
 
@@ -24,7 +25,7 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<EitherBody<B>>;
+    type Response = ServiceResponse<LoggedBody<B>>;
     type Error = Error;
     type InitError = ();
     type Transform = LoggingMiddleware<S>;
@@ -48,7 +49,7 @@ where
     S::Future: 'static,
     B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<EitherBody<B>>;
+    type Response = ServiceResponse<LoggedBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -58,14 +59,7 @@ where
         let svc = self.service.clone();
 
         Box::pin(async move {
-            let mut body = BytesMut::new();
-            let mut stream = req.take_payload();
-            while let Some(chunk) = stream.next().await {
-                body.extend_from_slice(&chunk?);
-            }
-            let body = body.freeze();
-
-            debug!("HTTP: {peer_addr} --> {method} {path} {version:?}\n{headers}BODY:{body:?}\n",
+            debug!("HTTP: {peer_addr} --> {method} {path} {version:?}\n{headers}",
                     peer_addr=req.connection_info().realip_remote_addr().unwrap_or(""),
                     method=req.method(),
                     path=req.path(),
@@ -73,45 +67,51 @@ where
                     headers=req.headers().iter()
                                         .map(|(key, value)| format!("{}: {}\n", key.as_str(), std::str::from_utf8(value.as_bytes()).unwrap_or("Invalid UTF-8 value")))
                                         .collect::<String>(),
-                    body=&body
                     );
 
-            let payload = body.slice(..);
-            let single_part: Result<Bytes, PayloadError> = Ok(payload);
-            let in_memory_stream = stream::once(future::ready(single_part));
-            let pinned_stream: Pin<Box<dyn Stream<Item=Result<Bytes, PayloadError>>>> = Box::pin(in_memory_stream);
-            let in_memory_payload: Payload = pinned_stream.into();
-            req.set_payload(in_memory_payload);
-
-
-            let resp = svc.call(req).await?.map_into_boxed_body();
-
-            let req_clone = resp.request().clone();
-            let resp_status = resp.status().clone();
-            let resp_error = resp.response().error().map(|error| format!(" Origin Error: {}", error)).unwrap_or("".to_string());
-            let resp_headers = resp.headers().clone();
-            let body = body::to_bytes(resp.into_body()).await.unwrap_or(Bytes::new());
-            
-            debug!("HTTP: <-- {status}{error}\n{headers}BODY:{body:?}\n",
-                  status=resp_status,
-                  error=resp_error,
-                  headers=resp_headers.iter()
+            // Body chunks are logged as the service reads them.
+            let payload = req.take_payload().inspect(|chunk| {
+                if let Ok(chunk) = chunk {
+                    debug!("HTTP: --> BODY:{:?}", chunk);
+                }
+            });
+            let payload: Pin<Box<dyn Stream<Item=Result<Bytes, PayloadError>>>> = Box::pin(payload);
+            req.set_payload(payload.into());
+
+            let resp = svc.call(req).await?;
+
+            debug!("HTTP: <-- {status}{error}\n{headers}",
+                  status=resp.status(),
+                  error=resp.response().error().map(|error| format!(" Origin Error: {}", error)).unwrap_or("".to_string()),
+                  headers=resp.headers().iter()
                                       .map(|(key, value)| format!("{}: {}\n", key.as_str(), std::str::from_utf8(value.as_bytes()).unwrap_or("Invalid UTF-8 value")))
                                       .collect::<String>(),
-                  body=&body
                 );
-            
-            let mut resp_clone = HttpResponseBuilder::new(resp_status);
-            for (header_name, header_value) in resp_headers {
-                resp_clone.insert_header((header_name.as_str(), header_value));
-            }
-            let resp_clone = resp_clone.body(body.to_vec());
-
-
-            Ok(ServiceResponse::new(
-                req_clone,
-                resp_clone.map_into_right_body(),
-            ))
+
+            Ok(resp.map_body(|_, body| LoggedBody { body }))
         })
     }
 }
+
+/// Response body that logs its chunks as they are sent.
+#[pin_project]
+pub struct LoggedBody<B> {
+    #[pin]
+    body: B,
+}
+
+impl<B: MessageBody> MessageBody for LoggedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let chunk = futures::ready!(self.project().body.poll_next(cx));
+        if let Some(Ok(chunk)) = &chunk {
+            debug!("HTTP: <-- BODY:{:?}", chunk);
+        }
+        Poll::Ready(chunk)
+    }
+}
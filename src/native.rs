@@ -0,0 +1,1421 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+
+use crate::columns::{self, ColumnType, Value};
+
+// Protocol revisions that change the packets layout we care about.
+pub const REVISION_WITH_TEMPORARY_TABLES: u64 = 50264;
+pub const REVISION_WITH_TOTAL_ROWS_IN_PROGRESS: u64 = 51554;
+pub const REVISION_WITH_BLOCK_INFO: u64 = 51903;
+pub const REVISION_WITH_CLIENT_INFO: u64 = 54032;
+pub const REVISION_WITH_SERVER_TIMEZONE: u64 = 54058;
+pub const REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO: u64 = 54060;
+pub const REVISION_WITH_SERVER_DISPLAY_NAME: u64 = 54372;
+pub const REVISION_WITH_VERSION_PATCH: u64 = 54401;
+pub const REVISION_WITH_CLIENT_WRITE_INFO: u64 = 54420;
+pub const REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS: u64 = 54429;
+pub const REVISION_WITH_INTERSERVER_SECRET: u64 = 54441;
+pub const REVISION_WITH_OPENTELEMETRY: u64 = 54442;
+pub const REVISION_WITH_X_FORWARDED_FOR_IN_CLIENT_INFO: u64 = 54443;
+pub const REVISION_WITH_REFERER_IN_CLIENT_INFO: u64 = 54447;
+pub const REVISION_WITH_DISTRIBUTED_DEPTH: u64 = 54448;
+pub const REVISION_WITH_INITIAL_QUERY_START_TIME: u64 = 54449;
+pub const REVISION_WITH_PARALLEL_REPLICAS: u64 = 54453;
+
+/// Highest revision we can decode. Hellos passing through the proxy are capped to it,
+/// so both sides negotiate a protocol we understand.
+pub const MAX_REVISION: u64 = REVISION_WITH_PARALLEL_REPLICAS;
+
+mod client {
+    pub const HELLO: u64 = 0;
+    pub const QUERY: u64 = 1;
+    pub const DATA: u64 = 2;
+    pub const CANCEL: u64 = 3;
+    pub const PING: u64 = 4;
+    pub const TABLES_STATUS_REQUEST: u64 = 5;
+    pub const KEEP_ALIVE: u64 = 6;
+    pub const SCALAR: u64 = 7;
+    pub const IGNORED_PART_UUIDS: u64 = 8;
+}
+
+mod server {
+    pub const HELLO: u64 = 0;
+    pub const DATA: u64 = 1;
+    pub const EXCEPTION: u64 = 2;
+    pub const PROGRESS: u64 = 3;
+    pub const PONG: u64 = 4;
+    pub const END_OF_STREAM: u64 = 5;
+    pub const PROFILE_INFO: u64 = 6;
+    pub const TOTALS: u64 = 7;
+    pub const EXTREMES: u64 = 8;
+    pub const TABLES_STATUS_RESPONSE: u64 = 9;
+    pub const LOG: u64 = 10;
+    pub const TABLE_COLUMNS: u64 = 11;
+    pub const PART_UUIDS: u64 = 12;
+    pub const READ_TASK_REQUEST: u64 = 13;
+    pub const PROFILE_EVENTS: u64 = 14;
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not enough bytes yet, decoding can't get further before the input is at least this long.
+    Incomplete(usize),
+    /// Valid traffic we can't decode, the connection has to fall back to raw forwarding.
+    Unsupported(String),
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Incomplete(_) => write!(f, "incomplete packet"),
+            DecodeError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            DecodeError::Invalid(what) => write!(f, "invalid packet: {}", what),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, DecodeError>;
+
+/// Side of the connection packets come from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sender {
+    Client,
+    Server,
+}
+
+impl Sender {
+    /// Whether `buf` holds nothing but complete packets.
+    fn whole_packets(self, mut buf: &[u8], state: &ProtocolState) -> bool {
+        while !buf.is_empty() {
+            let decoded = match self {
+                Sender::Client => decode_client(buf, state).map(|(_, len)| len),
+                Sender::Server => decode_server(buf, state).map(|(_, len)| len),
+            };
+            match decoded {
+                Ok(len) => buf = &buf[len..],
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Connection parameters the packets layout depends on.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolState {
+    /// Negotiated revision, zero until the hellos are exchanged.
+    pub revision: u64,
+    /// How data blocks of the current query are compressed, if they are.
+    pub compression: Option<CompressionMethod>,
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The next `len` bytes without consuming them.
+    pub fn peek(&self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::Incomplete(self.pos.saturating_add(len)));
+        }
+        Ok(&self.buf[self.pos..self.pos + len])
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.peek(len)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Everything left in the buffer.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    pub fn skip(&mut self, len: u64) -> Result<()> {
+        let len = usize::try_from(len).map_err(|_| DecodeError::Invalid("too long".to_string()))?;
+        self.bytes(len).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Invalid("varint is too long".to_string()))
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        let len = self.varint()?;
+        let len = usize::try_from(len).map_err(|_| DecodeError::Invalid("too long".to_string()))?;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+pub fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.put_u8(byte);
+            return;
+        }
+        buf.put_u8(byte | 0x80);
+    }
+}
+
+pub fn put_string(buf: &mut BytesMut, s: &str) {
+    put_varint(buf, s.len() as u64);
+    buf.put_slice(s.as_bytes());
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientHello {
+    pub client_name: String,
+    pub version_major: u64,
+    pub version_minor: u64,
+    pub revision: u64,
+    pub database: String,
+    pub user: String,
+    pub password: String,
+}
+
+impl ClientHello {
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            client_name: r.string()?,
+            version_major: r.varint()?,
+            version_minor: r.varint()?,
+            revision: r.varint()?,
+            database: r.string()?,
+            user: r.string()?,
+            password: r.string()?,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        put_varint(buf, client::HELLO);
+        put_string(buf, &self.client_name);
+        put_varint(buf, self.version_major);
+        put_varint(buf, self.version_minor);
+        put_varint(buf, self.revision);
+        put_string(buf, &self.database);
+        put_string(buf, &self.user);
+        put_string(buf, &self.password);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerHello {
+    pub server_name: String,
+    pub version_major: u64,
+    pub version_minor: u64,
+    pub revision: u64,
+    pub timezone: String,
+    pub display_name: String,
+    pub version_patch: u64,
+}
+
+impl ServerHello {
+    /// Hello of a server of the given `major.minor.patch` version, used when none was recorded.
+    pub fn with_version(version: &str) -> std::result::Result<Self, String> {
+        let parts = version.split('.')
+            .map(|part| part.parse::<u64>().map_err(|_| format!("invalid server version: {}", version)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let [major, minor, patch] = parts[..] else {
+            return Err(format!("server version must look like 23.8.1, got {}", version));
+        };
+        Ok(Self {
+            server_name: "ClickHouse".to_string(),
+            version_major: major,
+            version_minor: minor,
+            revision: MAX_REVISION,
+            timezone: "UTC".to_string(),
+            display_name: "network-replay-server".to_string(),
+            version_patch: patch,
+        })
+    }
+
+    /// Fields present in the hello depend on the client revision, not on the server one.
+    fn read(r: &mut Reader, client_revision: u64) -> Result<Self> {
+        let mut hello = Self {
+            server_name: r.string()?,
+            version_major: r.varint()?,
+            version_minor: r.varint()?,
+            revision: r.varint()?,
+            timezone: String::new(),
+            display_name: String::new(),
+            version_patch: 0,
+        };
+        if client_revision >= REVISION_WITH_SERVER_TIMEZONE {
+            hello.timezone = r.string()?;
+        }
+        if client_revision >= REVISION_WITH_SERVER_DISPLAY_NAME {
+            hello.display_name = r.string()?;
+        }
+        if client_revision >= REVISION_WITH_VERSION_PATCH {
+            hello.version_patch = r.varint()?;
+        }
+        Ok(hello)
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut, client_revision: u64) {
+        put_varint(buf, server::HELLO);
+        put_string(buf, &self.server_name);
+        put_varint(buf, self.version_major);
+        put_varint(buf, self.version_minor);
+        put_varint(buf, self.revision);
+        if client_revision >= REVISION_WITH_SERVER_TIMEZONE {
+            put_string(buf, &self.timezone);
+        }
+        if client_revision >= REVISION_WITH_SERVER_DISPLAY_NAME {
+            put_string(buf, &self.display_name);
+        }
+        if client_revision >= REVISION_WITH_VERSION_PATCH {
+            put_varint(buf, self.version_patch);
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub query_id: String,
+    pub settings: Vec<(String, String)>,
+    pub stage: u64,
+    pub compression: bool,
+    pub query: String,
+}
+
+impl Query {
+    /// Method the server compresses its blocks with, chosen by the `network_compression_method` setting.
+    pub fn compression_method(&self) -> Option<CompressionMethod> {
+        if !self.compression {
+            return None;
+        }
+        let method = self.settings.iter()
+            .find(|(name, _)| name == "network_compression_method")
+            .map(|(_, value)| value.to_ascii_lowercase());
+        Some(match method.as_deref() {
+            Some("zstd") => CompressionMethod::Zstd,
+            Some("none") => CompressionMethod::None,
+            _ => CompressionMethod::Lz4,
+        })
+    }
+
+    fn read(r: &mut Reader, revision: u64) -> Result<Self> {
+        let query_id = r.string()?;
+        if revision >= REVISION_WITH_CLIENT_INFO {
+            skip_client_info(r, revision)?;
+        }
+
+        if revision < REVISION_WITH_SETTINGS_SERIALIZED_AS_STRINGS {
+            return Err(DecodeError::Unsupported(format!("settings of revision {}", revision)));
+        }
+        let mut settings = Vec::new();
+        loop {
+            let name = r.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let _flags = r.varint()?;
+            settings.push((name, r.string()?));
+        }
+
+        if revision >= REVISION_WITH_INTERSERVER_SECRET {
+            let _secret = r.string()?;
+        }
+
+        Ok(Self {
+            query_id,
+            settings,
+            stage: r.varint()?,
+            compression: r.varint()? != 0,
+            query: r.string()?,
+        })
+    }
+}
+
+fn skip_client_info(r: &mut Reader, revision: u64) -> Result<()> {
+    const INTERFACE_TCP: u8 = 1;
+    const INTERFACE_HTTP: u8 = 2;
+
+    let query_kind = r.u8()?;
+    if query_kind == 0 {
+        return Ok(());
+    }
+
+    let _initial_user = r.string()?;
+    let _initial_query_id = r.string()?;
+    let _initial_address = r.string()?;
+    if revision >= REVISION_WITH_INITIAL_QUERY_START_TIME {
+        let _initial_query_start_time = r.u64()?;
+    }
+
+    let interface = r.u8()?;
+    match interface {
+        INTERFACE_TCP => {
+            let _os_user = r.string()?;
+            let _client_hostname = r.string()?;
+            let _client_name = r.string()?;
+            let _version_major = r.varint()?;
+            let _version_minor = r.varint()?;
+            let _revision = r.varint()?;
+        }
+        INTERFACE_HTTP => {
+            let _http_method = r.u8()?;
+            let _user_agent = r.string()?;
+            if revision >= REVISION_WITH_X_FORWARDED_FOR_IN_CLIENT_INFO {
+                let _forwarded_for = r.string()?;
+            }
+            if revision >= REVISION_WITH_REFERER_IN_CLIENT_INFO {
+                let _referer = r.string()?;
+            }
+        }
+        _ => {}
+    }
+
+    if revision >= REVISION_WITH_QUOTA_KEY_IN_CLIENT_INFO {
+        let _quota_key = r.string()?;
+    }
+    if revision >= REVISION_WITH_DISTRIBUTED_DEPTH {
+        let _distributed_depth = r.varint()?;
+    }
+    if interface == INTERFACE_TCP && revision >= REVISION_WITH_VERSION_PATCH {
+        let _version_patch = r.varint()?;
+    }
+    if revision >= REVISION_WITH_OPENTELEMETRY && r.u8()? != 0 {
+        let _trace_id = r.bytes(16)?;
+        let _span_id = r.u64()?;
+        let _tracestate = r.string()?;
+        let _trace_flags = r.u8()?;
+    }
+    if revision >= REVISION_WITH_PARALLEL_REPLICAS {
+        let _collaborate_with_initiator = r.varint()?;
+        let _count_participating_replicas = r.varint()?;
+        let _number_of_current_replica = r.varint()?;
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub type_name: String,
+    /// Serialized column data, empty for blocks without rows.
+    pub data: Bytes,
+}
+
+impl Column {
+    /// Decoded column values, fails for types we can't interpret.
+    pub fn values(&self, rows: u64) -> Result<Vec<Value>> {
+        if rows == 0 {
+            return Ok(Vec::new());
+        }
+        let column_type = ColumnType::parse(&self.type_name)?;
+        let mut r = Reader::new(&self.data);
+        column_type.skip_prefix(&mut r)?;
+        column_type.read_values(&mut r, rows)
+    }
+}
+
+/// Rows shown when a block is rendered as a table, the rest are only counted.
+const MAX_RENDERED_ROWS: usize = 20;
+const MAX_CELL_WIDTH: usize = 60;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub rows: u64,
+    pub columns: Vec<Column>,
+    /// Columns after one of a type we can't skip, their names, types and data are part of its data.
+    pub undecoded_columns: u64,
+    /// The whole block as serialized without compression.
+    pub data: Bytes,
+}
+
+impl Block {
+    /// With `bounded` the block ends with the buffer, so a column of a type we can't skip
+    /// takes the rest of it instead of failing the block.
+    fn read(r: &mut Reader, revision: u64, bounded: bool) -> Result<Self> {
+        let begin = r.position();
+        if revision >= REVISION_WITH_BLOCK_INFO {
+            loop {
+                match r.varint()? {
+                    0 => break,
+                    1 => { let _is_overflows = r.u8()?; }
+                    2 => { let _bucket_num = r.i32()?; }
+                    field => return Err(DecodeError::Invalid(format!("unknown block info field {}", field))),
+                }
+            }
+        }
+
+        let num_columns = r.varint()?;
+        let rows = r.varint()?;
+        let mut columns = Vec::new();
+        let mut undecoded_columns = 0;
+        for i in 0..num_columns {
+            let name = r.string()?;
+            let type_name = r.string()?;
+            let start = r.position();
+            if rows > 0 {
+                match skip_column(r, &type_name, rows) {
+                    Ok(()) => {}
+                    Err(DecodeError::Unsupported(_)) if bounded => {
+                        r.rest();
+                        columns.push(Column { name, type_name, data: Bytes::copy_from_slice(&r.buf[start..]) });
+                        undecoded_columns = num_columns - i - 1;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let data = Bytes::copy_from_slice(&r.buf[start..r.position()]);
+            columns.push(Column { name, type_name, data });
+        }
+
+        let data = Bytes::copy_from_slice(&r.buf[begin..r.position()]);
+        Ok(Self { rows, columns, undecoded_columns, data })
+    }
+}
+
+fn skip_column(r: &mut Reader, type_name: &str, rows: u64) -> Result<()> {
+    let column_type = ColumnType::parse(type_name)?;
+    column_type.skip_prefix(r)?;
+    column_type.skip_data(r, rows)
+}
+
+/// Renders the block as a text table, columns of types we can't interpret are dumped as hex below it.
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.columns.is_empty() {
+            return write!(f, "(empty block)");
+        }
+
+        let shown = usize::try_from(self.rows).unwrap_or(usize::MAX).min(MAX_RENDERED_ROWS);
+        let mut undecoded = Vec::new();
+        let mut table = Vec::new();
+        for column in self.columns.iter() {
+            let mut cells = vec![format!("{} {}", column.name, column.type_name)];
+            match column.values(self.rows) {
+                Ok(values) => cells.extend(values.iter().take(shown).map(|value| cell_text(value.cell()))),
+                Err(_) => {
+                    cells.extend(std::iter::repeat_n("?".to_string(), shown));
+                    undecoded.push(column);
+                }
+            }
+            table.push(cells);
+        }
+
+        let widths = table.iter()
+            .map(|cells| cells.iter().map(|cell| cell.chars().count()).max().unwrap_or(0))
+            .collect::<Vec<_>>();
+        for row in 0..=shown {
+            let line = table.iter()
+                .zip(widths.iter())
+                .map(|(cells, width)| format!("{:<width$}", cells[row], width = width))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            writeln!(f, "{}", line.trim_end())?;
+            if row == 0 {
+                writeln!(f, "{}", widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<_>>().join("-+-"))?;
+            }
+        }
+        if self.rows > shown as u64 {
+            writeln!(f, "... {} more rows", self.rows - shown as u64)?;
+        }
+        for column in undecoded {
+            writeln!(f, "{} {}: {}", column.name, column.type_name, columns::hex(&column.data))?;
+        }
+        if self.undecoded_columns > 0 {
+            writeln!(f, "{} more columns are part of the last hex", self.undecoded_columns)?;
+        }
+        write!(f, "{} rows", self.rows)
+    }
+}
+
+fn cell_text(text: String) -> String {
+    let text = text.replace('\n', "\\n");
+    match text.char_indices().nth(MAX_CELL_WIDTH) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+impl fmt::Display for DataPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.table_name.is_empty() {
+            writeln!(f, "table {}", self.table_name)?;
+        }
+        write!(f, "{}", self.block)
+    }
+}
+
+/// Data-like packet: temporary table name followed by a block.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct DataPacket {
+    pub table_name: String,
+    pub block: Block,
+}
+
+impl DataPacket {
+    /// Blocks of Data, Totals and Extremes packets are compressed if the query asked for it,
+    /// blocks of Log and ProfileEvents packets never are.
+    fn read(r: &mut Reader, state: &ProtocolState, sender: Sender, compressible: bool) -> Result<Self> {
+        let table_name = if state.revision >= REVISION_WITH_TEMPORARY_TABLES { r.string()? } else { String::new() };
+        if !compressible || state.compression.is_none() {
+            // Nothing but the columns tells where an uncompressed block ends.
+            return Ok(Self { table_name, block: Block::read(r, state.revision, false)? });
+        }
+
+        let mut block = CompressedBlock::default();
+        loop {
+            if let Some(block) = block.read_frame(r, state, sender)? {
+                return Ok(Self { table_name, block });
+            }
+        }
+    }
+
+    fn encode(&self, packet_type: u64, buf: &mut BytesMut, state: &ProtocolState, compressible: bool) {
+        put_varint(buf, packet_type);
+        if state.revision >= REVISION_WITH_TEMPORARY_TABLES {
+            put_string(buf, &self.table_name);
+        }
+        match state.compression {
+            Some(method) if compressible => {
+                for chunk in self.block.data.chunks(MAX_FRAME_SIZE) {
+                    write_frame(buf, chunk, method);
+                }
+            }
+            _ => buf.put_slice(&self.block.data),
+        }
+    }
+}
+
+/// Largest block part ClickHouse puts into a single compressed frame.
+const MAX_FRAME_SIZE: usize = 1 << 20;
+/// Checksum, method byte, compressed and decompressed sizes.
+const FRAME_HEADER_SIZE: usize = 16 + 1 + 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionMethod {
+    /// Frames are still written, but their payload is stored as is.
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionMethod {
+    fn byte(self) -> u8 {
+        match self {
+            CompressionMethod::None => 0x02,
+            CompressionMethod::Lz4 => 0x82,
+            CompressionMethod::Zstd => 0x90,
+        }
+    }
+}
+
+/// ClickHouse checksum of a frame: CityHash128 v1.0.2 written as two little-endian halves.
+fn frame_checksum(frame: &[u8]) -> [u8; 16] {
+    let hash = cityhash_rs::cityhash_102_128(frame);
+    let mut checksum = [0u8; 16];
+    checksum[..8].copy_from_slice(&((hash >> 64) as u64).to_le_bytes());
+    checksum[8..].copy_from_slice(&(hash as u64).to_le_bytes());
+    checksum
+}
+
+/// Frames of a compressed block read so far, each of them is decompressed once.
+#[derive(Debug, Default)]
+struct CompressedBlock {
+    decompressed: Vec<u8>,
+    /// Set once a column turned out to be of a type we can't skip, only the frames tell where the block ends then.
+    undecodable: bool,
+}
+
+impl CompressedBlock {
+    /// Reads the next frame of the block, returns the block once it is complete.
+    /// Nothing is kept of a frame that isn't complete yet.
+    fn read_frame(&mut self, r: &mut Reader, state: &ProtocolState, sender: Sender) -> Result<Option<Block>> {
+        if self.undecodable {
+            // A block always ends with a frame, a frame right after it continues the block.
+            if !starts_frame(r, state, sender)? {
+                return Block::read(&mut Reader::new(&self.decompressed), state.revision, true).map(Some);
+            }
+            self.decompressed.extend_from_slice(&read_frame(r)?);
+            return Ok(None);
+        }
+
+        self.decompressed.extend_from_slice(&read_frame(r)?);
+        let mut block_reader = Reader::new(&self.decompressed);
+        match Block::read(&mut block_reader, state.revision, false) {
+            Ok(block) if block_reader.position() == self.decompressed.len() => Ok(Some(block)),
+            Ok(_) => Err(DecodeError::Invalid("garbage after compressed block".to_string())),
+            Err(DecodeError::Incomplete(_)) => Ok(None),
+            Err(DecodeError::Unsupported(_)) => {
+                self.undecodable = true;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Whether the next bytes are the header of a compressed frame rather than the next packet.
+/// Some bytes, but fewer than the header up to its method byte, end the block only if they are whole packets.
+fn starts_frame(r: &Reader, state: &ProtocolState, sender: Sender) -> Result<bool> {
+    let rest = &r.buf[r.pos..];
+    match r.peek(17) {
+        Ok(header) => Ok([CompressionMethod::None, CompressionMethod::Lz4, CompressionMethod::Zstd].iter().any(|method| method.byte() == header[16])),
+        Err(_) if !rest.is_empty() && sender.whole_packets(rest, state) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads one compressed frame, verifies its checksum and returns the decompressed payload.
+fn read_frame(r: &mut Reader) -> Result<Vec<u8>> {
+    let checksum = r.bytes(16)?;
+    let header = r.bytes(FRAME_HEADER_SIZE - 16)?;
+    let compressed_size = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+    let decompressed_size = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    if compressed_size < header.len() {
+        return Err(DecodeError::Invalid(format!("compressed frame size {} is too small", compressed_size)));
+    }
+    let payload = r.bytes(compressed_size - header.len())?;
+
+    let frame = [header, payload].concat();
+    if frame_checksum(&frame) != checksum {
+        return Err(DecodeError::Invalid("compressed frame checksum mismatch".to_string()));
+    }
+
+    let decompressed = match header[0] {
+        0x02 => payload.to_vec(),
+        0x82 => lz4_flex::block::decompress(payload, decompressed_size)
+            .map_err(|e| DecodeError::Invalid(format!("lz4: {}", e)))?,
+        0x90 => zstd::bulk::decompress(payload, decompressed_size)
+            .map_err(|e| DecodeError::Invalid(format!("zstd: {}", e)))?,
+        method => return Err(DecodeError::Unsupported(format!("compression method {:#04x}", method))),
+    };
+    if decompressed.len() != decompressed_size {
+        return Err(DecodeError::Invalid("compressed frame size mismatch".to_string()));
+    }
+    Ok(decompressed)
+}
+
+fn write_frame(buf: &mut BytesMut, data: &[u8], method: CompressionMethod) {
+    let payload = match method {
+        CompressionMethod::None => data.to_vec(),
+        CompressionMethod::Lz4 => lz4_flex::block::compress(data),
+        CompressionMethod::Zstd => zstd::bulk::compress(data, 1).expect("zstd compression failed"),
+    };
+
+    let mut frame = BytesMut::with_capacity(FRAME_HEADER_SIZE - 16 + payload.len());
+    frame.put_u8(method.byte());
+    frame.put_u32_le((FRAME_HEADER_SIZE - 16 + payload.len()) as u32);
+    frame.put_u32_le(data.len() as u32);
+    frame.put_slice(&payload);
+
+    buf.put_slice(&frame_checksum(&frame));
+    buf.put_slice(&frame);
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub code: i32,
+    pub name: String,
+    pub message: String,
+    pub stack_trace: String,
+    pub nested: Option<Box<Exception>>,
+}
+
+impl Exception {
+    pub fn new(code: i32, message: String) -> Self {
+        Self {
+            code,
+            name: "DB::Exception".to_string(),
+            message,
+            stack_trace: String::new(),
+            nested: None,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        put_varint(buf, server::EXCEPTION);
+        self.encode_body(buf);
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) {
+        buf.put_i32_le(self.code);
+        put_string(buf, &self.name);
+        put_string(buf, &self.message);
+        put_string(buf, &self.stack_trace);
+        match &self.nested {
+            Some(nested) => {
+                buf.put_u8(1);
+                nested.encode_body(buf);
+            }
+            None => buf.put_u8(0),
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Self> {
+        let mut exception = Self {
+            code: r.i32()?,
+            name: r.string()?,
+            message: r.string()?,
+            stack_trace: r.string()?,
+            nested: None,
+        };
+        if r.u8()? != 0 {
+            exception.nested = Some(Box::new(Self::read(r)?));
+        }
+        Ok(exception)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub rows: u64,
+    pub bytes: u64,
+    pub total_rows: u64,
+    pub written_rows: u64,
+    pub written_bytes: u64,
+}
+
+impl Progress {
+    fn read(r: &mut Reader, revision: u64) -> Result<Self> {
+        let mut progress = Self { rows: r.varint()?, bytes: r.varint()?, ..Self::default() };
+        if revision >= REVISION_WITH_TOTAL_ROWS_IN_PROGRESS {
+            progress.total_rows = r.varint()?;
+        }
+        if revision >= REVISION_WITH_CLIENT_WRITE_INFO {
+            progress.written_rows = r.varint()?;
+            progress.written_bytes = r.varint()?;
+        }
+        Ok(progress)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
+pub struct ProfileInfo {
+    pub rows: u64,
+    pub blocks: u64,
+    pub bytes: u64,
+    pub applied_limit: bool,
+    pub rows_before_limit: u64,
+    pub calculated_rows_before_limit: bool,
+}
+
+impl ProfileInfo {
+    fn read(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            rows: r.varint()?,
+            blocks: r.varint()?,
+            bytes: r.varint()?,
+            applied_limit: r.u8()? != 0,
+            rows_before_limit: r.varint()?,
+            calculated_rows_before_limit: r.u8()? != 0,
+        })
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum ClientPacket {
+    Hello(ClientHello),
+    Query(Query),
+    Data(DataPacket),
+    Cancel,
+    Ping,
+    TablesStatusRequest,
+    KeepAlive,
+    Scalar(DataPacket),
+    IgnoredPartUuids,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum ServerPacket {
+    Hello(ServerHello),
+    Data(DataPacket),
+    Exception(Exception),
+    Progress(Progress),
+    Pong,
+    EndOfStream,
+    ProfileInfo(ProfileInfo),
+    Totals(DataPacket),
+    Extremes(DataPacket),
+    TablesStatusResponse,
+    Log(DataPacket),
+    TableColumns,
+    PartUuids,
+    ReadTaskRequest,
+    ProfileEvents(DataPacket),
+}
+
+pub fn encode_pong(buf: &mut BytesMut) {
+    put_varint(buf, server::PONG);
+}
+
+/// Blocks are rendered as tables, other packets as their debug form.
+impl fmt::Display for ClientPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientPacket::Data(data) => write!(f, "Data\n{}", data),
+            ClientPacket::Scalar(data) => write!(f, "Scalar\n{}", data),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+impl fmt::Display for ServerPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerPacket::Data(data) => write!(f, "Data\n{}", data),
+            ServerPacket::Totals(data) => write!(f, "Totals\n{}", data),
+            ServerPacket::Extremes(data) => write!(f, "Extremes\n{}", data),
+            ServerPacket::Log(data) => write!(f, "Log\n{}", data),
+            ServerPacket::ProfileEvents(data) => write!(f, "ProfileEvents\n{}", data),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+impl ServerPacket {
+    /// Last packet the server sends in answer to a query.
+    pub fn ends_query(&self) -> bool {
+        matches!(self, ServerPacket::EndOfStream | ServerPacket::Exception(_))
+    }
+}
+
+/// Decodes one client packet from the beginning of `buf`, returns it with its length in bytes.
+pub fn decode_client(buf: &[u8], state: &ProtocolState) -> Result<(ClientPacket, usize)> {
+    let mut r = Reader::new(buf);
+    let packet = match r.varint()? {
+        client::HELLO => ClientPacket::Hello(ClientHello::read(&mut r)?),
+        client::QUERY => ClientPacket::Query(Query::read(&mut r, state.revision)?),
+        client::DATA => ClientPacket::Data(DataPacket::read(&mut r, state, Sender::Client, true)?),
+        client::CANCEL => ClientPacket::Cancel,
+        client::PING => ClientPacket::Ping,
+        client::TABLES_STATUS_REQUEST => {
+            for _ in 0..r.varint()? {
+                let _database = r.string()?;
+                let _table = r.string()?;
+            }
+            ClientPacket::TablesStatusRequest
+        }
+        client::KEEP_ALIVE => ClientPacket::KeepAlive,
+        client::SCALAR => ClientPacket::Scalar(DataPacket::read(&mut r, state, Sender::Client, true)?),
+        client::IGNORED_PART_UUIDS => {
+            let len = r.varint()?;
+            r.skip(len.saturating_mul(16))?;
+            ClientPacket::IgnoredPartUuids
+        }
+        packet => return Err(DecodeError::Unsupported(format!("client packet {}", packet))),
+    };
+    Ok((packet, r.position()))
+}
+
+/// Decodes one server packet from the beginning of `buf`, returns it with its length in bytes.
+pub fn decode_server(buf: &[u8], state: &ProtocolState) -> Result<(ServerPacket, usize)> {
+    let mut r = Reader::new(buf);
+    let packet = match r.varint()? {
+        server::HELLO => ServerPacket::Hello(ServerHello::read(&mut r, state.revision)?),
+        server::DATA => ServerPacket::Data(DataPacket::read(&mut r, state, Sender::Server, true)?),
+        server::EXCEPTION => ServerPacket::Exception(Exception::read(&mut r)?),
+        server::PROGRESS => ServerPacket::Progress(Progress::read(&mut r, state.revision)?),
+        server::PONG => ServerPacket::Pong,
+        server::END_OF_STREAM => ServerPacket::EndOfStream,
+        server::PROFILE_INFO => ServerPacket::ProfileInfo(ProfileInfo::read(&mut r)?),
+        server::TOTALS => ServerPacket::Totals(DataPacket::read(&mut r, state, Sender::Server, true)?),
+        server::EXTREMES => ServerPacket::Extremes(DataPacket::read(&mut r, state, Sender::Server, true)?),
+        server::TABLES_STATUS_RESPONSE => {
+            for _ in 0..r.varint()? {
+                let _database = r.string()?;
+                let _table = r.string()?;
+                if r.u8()? != 0 {
+                    let _absolute_delay = r.varint()?;
+                }
+            }
+            ServerPacket::TablesStatusResponse
+        }
+        server::LOG => ServerPacket::Log(DataPacket::read(&mut r, state, Sender::Server, false)?),
+        server::TABLE_COLUMNS => {
+            let _external_table_name = r.string()?;
+            let _columns = r.string()?;
+            ServerPacket::TableColumns
+        }
+        server::PART_UUIDS => {
+            let len = r.varint()?;
+            r.skip(len.saturating_mul(16))?;
+            ServerPacket::PartUuids
+        }
+        server::READ_TASK_REQUEST => ServerPacket::ReadTaskRequest,
+        server::PROFILE_EVENTS => ServerPacket::ProfileEvents(DataPacket::read(&mut r, state, Sender::Server, false)?),
+        packet => return Err(DecodeError::Unsupported(format!("server packet {}", packet))),
+    };
+    Ok((packet, r.position()))
+}
+
+/// What `PacketReader` found at the start of the buffer.
+#[derive(Debug)]
+pub enum Decoded<P> {
+    /// A packet and its number of bytes, or the number of its last bytes if it was passed on in parts.
+    Packet(P, usize),
+    /// The next bytes of a packet whose compressed block is still arriving: its header or whole frames.
+    Part(usize),
+}
+
+type Decode<P> = fn(&[u8], &ProtocolState) -> Result<(P, usize)>;
+/// Makes the packet of a type whose block may be compressed.
+type DataPacketOfType<P> = fn(u64) -> Option<fn(DataPacket) -> P>;
+
+/// Decodes the packets of one direction of a connection as its bytes arrive. Compressed blocks are taken
+/// frame by frame, each frame is decompressed once and can be passed on before the rest of its block arrives.
+#[derive(Debug, Default)]
+pub struct PacketReader {
+    /// Type, table name and frames so far of a packet passed on in parts.
+    pending: Option<(u64, String, CompressedBlock)>,
+}
+
+impl PacketReader {
+    pub fn client(&mut self, buf: &[u8], state: &ProtocolState) -> Result<Decoded<ClientPacket>> {
+        self.next(buf, state, Sender::Client, decode_client, |packet_type| match packet_type {
+            client::DATA => Some(ClientPacket::Data),
+            client::SCALAR => Some(ClientPacket::Scalar),
+            _ => None,
+        })
+    }
+
+    pub fn server(&mut self, buf: &[u8], state: &ProtocolState) -> Result<Decoded<ServerPacket>> {
+        self.next(buf, state, Sender::Server, decode_server, |packet_type| match packet_type {
+            server::DATA => Some(ServerPacket::Data),
+            server::TOTALS => Some(ServerPacket::Totals),
+            server::EXTREMES => Some(ServerPacket::Extremes),
+            _ => None,
+        })
+    }
+
+    /// Packets of the types `data_packet` knows are passed on in parts if their blocks are compressed,
+    /// all others are decoded whole with `decode`.
+    fn next<P>(&mut self, buf: &[u8], state: &ProtocolState, sender: Sender, decode: Decode<P>, data_packet: DataPacketOfType<P>) -> Result<Decoded<P>> {
+        let mut r = Reader::new(buf);
+        let (packet_type, table_name, mut block) = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let packet_type = r.varint()?;
+                if state.compression.is_none() || data_packet(packet_type).is_none() {
+                    return decode(buf, state).map(|(packet, len)| Decoded::Packet(packet, len));
+                }
+                let table_name = if state.revision >= REVISION_WITH_TEMPORARY_TABLES { r.string()? } else { String::new() };
+                (packet_type, table_name, CompressedBlock::default())
+            }
+        };
+        loop {
+            let start = r.position();
+            match block.read_frame(&mut r, state, sender) {
+                Ok(Some(block)) => {
+                    let packet = data_packet(packet_type).unwrap()(DataPacket { table_name, block });
+                    return Ok(Decoded::Packet(packet, r.position()));
+                }
+                Ok(None) => {}
+                Err(DecodeError::Incomplete(needed)) => {
+                    self.pending = Some((packet_type, table_name, block));
+                    return if start > 0 { Ok(Decoded::Part(start)) } else { Err(DecodeError::Incomplete(needed)) };
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Writes a decoded server packet for a client with the given protocol state: blocks are encoded again,
+/// compressed with its method or not, other packets are copied from their `raw` bytes.
+pub fn encode_server_packet(packet: &ServerPacket, raw: &[u8], state: &ProtocolState, buf: &mut BytesMut) {
+    match packet {
+        ServerPacket::Data(data) => data.encode(server::DATA, buf, state, true),
+        ServerPacket::Totals(data) => data.encode(server::TOTALS, buf, state, true),
+        ServerPacket::Extremes(data) => data.encode(server::EXTREMES, buf, state, true),
+        _ => buf.put_slice(raw),
+    }
+}
+
+/// Re-encodes a sequence of server packets recorded with the `from` state for a client with the `to` state.
+pub fn transcode_server_packets(mut buf: &[u8], from: &ProtocolState, to: &ProtocolState) -> Result<BytesMut> {
+    let mut out = BytesMut::with_capacity(buf.len());
+    while !buf.is_empty() {
+        let (packet, len) = decode_server(buf, from)?;
+        encode_server_packet(&packet, &buf[..len], to, &mut out);
+        buf = &buf[len..];
+    }
+    Ok(out)
+}
+
+/// Offset of the last of the recorded server packets.
+pub fn last_server_packet(buf: &[u8], state: &ProtocolState) -> Result<usize> {
+    let (mut offset, mut last) = (0, 0);
+    while offset < buf.len() {
+        let (_, len) = decode_server(&buf[offset..], state)?;
+        last = offset;
+        offset += len;
+    }
+    Ok(last)
+}
+
+/// Human-readable form of recorded server packets, the undecodable rest is dumped as hex.
+pub fn render_server_packets(mut buf: &[u8], state: &ProtocolState) -> String {
+    let mut out = String::new();
+    while !buf.is_empty() {
+        match decode_server(buf, state) {
+            Ok((packet, len)) => {
+                out.push_str(&format!("{}\n", packet));
+                buf = &buf[len..];
+            }
+            Err(e) => {
+                out.push_str(&format!("{}: {}\n", e, columns::hex(buf)));
+                break;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: ProtocolState = ProtocolState { revision: MAX_REVISION, compression: None };
+
+    /// Block with the `n UInt64` column holding 1 and 2 and the `s Array(String)` column holding `['a']` and `[]`.
+    fn block_bytes() -> BytesMut {
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, 1);
+        buf.put_u8(0);
+        put_varint(&mut buf, 2);
+        buf.put_i32_le(-1);
+        put_varint(&mut buf, 0);
+        put_varint(&mut buf, 2);
+        put_varint(&mut buf, 2);
+        put_string(&mut buf, "n");
+        put_string(&mut buf, "UInt64");
+        buf.put_u64_le(1);
+        buf.put_u64_le(2);
+        put_string(&mut buf, "s");
+        put_string(&mut buf, "Array(String)");
+        buf.put_u64_le(1);
+        buf.put_u64_le(1);
+        put_string(&mut buf, "a");
+        buf
+    }
+
+    fn data_packet_bytes(block: &[u8], state: &ProtocolState) -> BytesMut {
+        let data = DataPacket {
+            table_name: String::new(),
+            block: Block::read(&mut Reader::new(block), state.revision, true).unwrap(),
+        };
+        let mut buf = BytesMut::new();
+        data.encode(server::DATA, &mut buf, state, true);
+        buf
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, value);
+            let mut r = Reader::new(&buf);
+            assert_eq!(r.varint().unwrap(), value);
+            assert_eq!(r.position(), buf.len());
+        }
+        assert!(matches!(Reader::new(&[0x80]).varint(), Err(DecodeError::Incomplete(_))));
+    }
+
+    #[test]
+    fn hellos() {
+        let client = ClientHello {
+            client_name: "ClickHouse client".to_string(),
+            version_major: 23,
+            version_minor: 8,
+            revision: MAX_REVISION,
+            database: "default".to_string(),
+            user: "default".to_string(),
+            password: String::new(),
+        };
+        let mut buf = BytesMut::new();
+        client.encode(&mut buf);
+        let (packet, len) = decode_client(&buf, &ProtocolState::default()).unwrap();
+        assert_eq!(len, buf.len());
+        let ClientPacket::Hello(decoded) = packet else { panic!("expected hello, got {:?}", packet) };
+        assert_eq!((decoded.client_name.as_str(), decoded.revision, decoded.database.as_str()), ("ClickHouse client", MAX_REVISION, "default"));
+
+        let server = ServerHello {
+            server_name: "ClickHouse".to_string(),
+            version_major: 23,
+            version_minor: 8,
+            revision: MAX_REVISION,
+            timezone: "UTC".to_string(),
+            display_name: "replay".to_string(),
+            version_patch: 1,
+        };
+        // An old client gets neither timezone nor display name.
+        for (client_revision, timezone) in [(MAX_REVISION, "UTC"), (REVISION_WITH_SERVER_TIMEZONE - 1, "")] {
+            let mut buf = BytesMut::new();
+            server.encode(&mut buf, client_revision);
+            let state = ProtocolState { revision: client_revision, compression: None };
+            let (packet, len) = decode_server(&buf, &state).unwrap();
+            assert_eq!(len, buf.len());
+            let ServerPacket::Hello(decoded) = packet else { panic!("expected hello, got {:?}", packet) };
+            assert_eq!(decoded.timezone, timezone);
+        }
+    }
+
+    #[test]
+    fn data_packet() {
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, server::DATA);
+        put_string(&mut buf, "");
+        buf.put_slice(&block_bytes());
+        put_varint(&mut buf, server::END_OF_STREAM);
+
+        let (packet, len) = decode_server(&buf, &STATE).unwrap();
+        assert_eq!(len, buf.len() - 1);
+        let ServerPacket::Data(data) = packet else { panic!("expected data, got {:?}", packet) };
+        assert_eq!(data.block.rows, 2);
+        assert_eq!(data.block.columns.iter().map(|column| column.name.as_str()).collect::<Vec<_>>(), ["n", "s"]);
+        assert_eq!(data.block.columns[1].data.len(), 8 + 8 + 2);
+
+        let (packet, _) = decode_server(&buf[len..], &STATE).unwrap();
+        assert!(packet.ends_query());
+        for cut in [1, 10, len - 1] {
+            assert!(matches!(decode_server(&buf[..cut], &STATE), Err(DecodeError::Incomplete(_))), "{}", cut);
+        }
+    }
+
+    #[test]
+    fn frame_checksum_is_pinned() {
+        // Regression values: a change of the CityHash variant or of the order of its halves breaks every frame.
+        assert_eq!(columns::hex(&frame_checksum(b"")), "0x2b9ac064fc9df03d291ee592c340b53c");
+        let mut buf = BytesMut::new();
+        write_frame(&mut buf, b"hello", CompressionMethod::None);
+        assert_eq!(columns::hex(&buf), "0x6bd486683f80652ca2c707484941f28d020e0000000500000068656c6c6f");
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let data = b"select number from system.numbers limit 100 ".repeat(50);
+        for method in [CompressionMethod::None, CompressionMethod::Lz4, CompressionMethod::Zstd] {
+            let mut buf = BytesMut::new();
+            write_frame(&mut buf, &data, method);
+            assert_eq!(buf[16], method.byte());
+            let mut r = Reader::new(&buf);
+            assert_eq!(read_frame(&mut r).unwrap(), data, "{:?}", method);
+            assert_eq!(r.position(), buf.len());
+            assert!(matches!(read_frame(&mut Reader::new(&buf[..buf.len() - 1])), Err(DecodeError::Incomplete(_))));
+        }
+    }
+
+    #[test]
+    fn frame_checksum_mismatch() {
+        let mut buf = BytesMut::new();
+        write_frame(&mut buf, b"hello", CompressionMethod::None);
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(matches!(read_frame(&mut Reader::new(&buf)), Err(DecodeError::Invalid(_))));
+
+        let mut buf = BytesMut::new();
+        write_frame(&mut buf, b"hello", CompressionMethod::Lz4);
+        buf[0] ^= 1;
+        assert!(matches!(read_frame(&mut Reader::new(&buf)), Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn data_packet_round_trip() {
+        let block = block_bytes();
+        for compression in [None, Some(CompressionMethod::None), Some(CompressionMethod::Lz4), Some(CompressionMethod::Zstd)] {
+            let state = ProtocolState { compression, ..STATE };
+            let buf = data_packet_bytes(&block, &state);
+            let (packet, len) = decode_server(&buf, &state).unwrap();
+            assert_eq!(len, buf.len());
+            let ServerPacket::Data(data) = packet else { panic!("expected data, got {:?}", packet) };
+            assert_eq!(data.block.rows, 2);
+            assert_eq!(data.block.data.as_ref(), block.as_ref());
+        }
+    }
+
+    #[test]
+    fn transcode_between_compression_methods() {
+        let block = block_bytes();
+        let lz4 = ProtocolState { compression: Some(CompressionMethod::Lz4), ..STATE };
+        let mut recorded = data_packet_bytes(&block, &lz4);
+        put_varint(&mut recorded, server::END_OF_STREAM);
+
+        let plain = transcode_server_packets(&recorded, &lz4, &STATE).unwrap();
+        let mut expected = data_packet_bytes(&block, &STATE);
+        put_varint(&mut expected, server::END_OF_STREAM);
+        assert_eq!(plain, expected);
+        assert_eq!(transcode_server_packets(&plain, &STATE, &lz4).unwrap(), recorded);
+        assert_eq!(last_server_packet(&recorded, &lz4).unwrap(), recorded.len() - 1);
+    }
+
+    /// Block with `n UInt64` holding 1 and 2, `s Array(String)` holding `['a']` and `[]`,
+    /// then a column of a type we can't decode and a `t String` column.
+    fn unknown_type_block(unknown_data: &[u8]) -> BytesMut {
+        let mut buf = block_bytes();
+        // Number of columns, after the eight bytes of block info.
+        buf[8] = 4;
+        put_string(&mut buf, "j");
+        put_string(&mut buf, "Object('json')");
+        buf.put_slice(unknown_data);
+        put_string(&mut buf, "t");
+        put_string(&mut buf, "String");
+        put_string(&mut buf, "a");
+        put_string(&mut buf, "b");
+        buf
+    }
+
+    /// Adds a `t String` column holding a string longer than a frame and an empty one to the block.
+    fn buf_put_long_string_column(buf: &mut BytesMut) {
+        buf[8] += 1;
+        put_string(buf, "t");
+        put_string(buf, "String");
+        put_string(buf, &"a".repeat(MAX_FRAME_SIZE));
+        put_string(buf, "");
+    }
+
+    #[test]
+    fn compressed_block_with_unknown_type() {
+        let block = unknown_type_block(b"\x01\x02");
+        for method in [CompressionMethod::None, CompressionMethod::Lz4] {
+            let state = ProtocolState { compression: Some(method), ..STATE };
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, server::DATA);
+            put_string(&mut buf, "");
+            write_frame(&mut buf, &block, method);
+            put_varint(&mut buf, server::END_OF_STREAM);
+
+            let (packet, len) = decode_server(&buf, &state).unwrap();
+            assert_eq!(len, buf.len() - 1);
+            let ServerPacket::Data(data) = &packet else { panic!("expected data, got {:?}", packet) };
+            assert_eq!(data.block.data.as_ref(), block.as_ref());
+            assert_eq!(data.block.columns.iter().map(|column| column.name.as_str()).collect::<Vec<_>>(), ["n", "s", "j"]);
+            assert_eq!(data.block.undecoded_columns, 1);
+            assert_eq!(last_server_packet(&buf, &state).unwrap(), len);
+
+            let rendered = render_server_packets(&buf, &state);
+            assert!(rendered.contains("j Object('json'): 0x0102"), "{}", rendered);
+            assert!(rendered.contains("1 more columns are part of the last hex"), "{}", rendered);
+            assert!(rendered.ends_with("EndOfStream\n"), "{}", rendered);
+        }
+
+        // Without frames nothing tells where the block ends.
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, server::DATA);
+        put_string(&mut buf, "");
+        buf.put_slice(&block);
+        assert!(matches!(decode_server(&buf, &STATE), Err(DecodeError::Unsupported(_))));
+    }
+
+    #[test]
+    fn block_with_unknown_type_over_several_frames() {
+        let block = unknown_type_block(&vec![7; MAX_FRAME_SIZE]);
+        let state = ProtocolState { compression: Some(CompressionMethod::Lz4), ..STATE };
+        let mut buf = data_packet_bytes(&block, &state);
+        let len = buf.len();
+        put_varint(&mut buf, server::END_OF_STREAM);
+
+        let (packet, decoded_len) = decode_server(&buf, &state).unwrap();
+        assert_eq!(decoded_len, len);
+        let ServerPacket::Data(data) = packet else { panic!("expected data, got {:?}", packet) };
+        assert_eq!(data.block.data.len(), block.len());
+
+        // Replayed to a client without compression and back.
+        let plain = transcode_server_packets(&buf, &state, &STATE).unwrap();
+        assert_eq!(&plain[plain.len() - block.len() - 1..plain.len() - 1], block.as_ref());
+        assert!(matches!(decode_server(&buf[..len - 1], &state), Err(DecodeError::Incomplete(_))));
+    }
+
+    /// Feeds `buf` to a `PacketReader` in reads of 64 KiB the way the proxy does,
+    /// returns what it decoded with the number of bytes received by then.
+    fn read_in_parts(buf: &[u8], state: &ProtocolState) -> Vec<(usize, Decoded<ServerPacket>)> {
+        let mut reader = PacketReader::default();
+        let (mut received, mut consumed, mut needed) = (0, 0, 1);
+        let mut decoded = Vec::new();
+        while consumed < buf.len() {
+            if received - consumed < needed {
+                assert!(received < buf.len(), "{} more bytes needed at the end", needed);
+                received = (received + (1 << 16)).min(buf.len());
+                continue;
+            }
+            match reader.server(&buf[consumed..received], state) {
+                Ok(packet) => {
+                    consumed += match &packet { Decoded::Packet(_, len) | Decoded::Part(len) => *len };
+                    needed = 1;
+                    decoded.push((received, packet));
+                }
+                Err(DecodeError::Incomplete(len)) => needed = len,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        decoded
+    }
+
+    #[test]
+    fn compressed_blocks_are_read_frame_by_frame() {
+        let mut long_string_block = block_bytes();
+        buf_put_long_string_column(&mut long_string_block);
+        // Frames that aren't compressed for them to arrive in several reads.
+        let state = ProtocolState { compression: Some(CompressionMethod::None), ..STATE };
+        for block in [long_string_block, unknown_type_block(&vec![7; MAX_FRAME_SIZE])] {
+            let mut buf = data_packet_bytes(&block, &state);
+            let len = buf.len();
+            put_varint(&mut buf, server::END_OF_STREAM);
+
+            let decoded = read_in_parts(&buf, &state);
+            // The first frame is passed on before the second one arrived.
+            assert!(decoded.iter().any(|(received, packet)| matches!(packet, Decoded::Part(_)) && *received < len));
+            let [.., (_, Decoded::Packet(ServerPacket::Data(data), _)), (_, Decoded::Packet(ServerPacket::EndOfStream, 1))] = &decoded[..] else {
+                panic!("expected data and end of stream, got {:?}", decoded);
+            };
+            assert_eq!(data.block.data.as_ref(), block.as_ref());
+        }
+    }
+
+    #[test]
+    fn render_block() {
+        let rendered = render_server_packets(&data_packet_bytes(&block_bytes(), &STATE), &STATE);
+        assert_eq!(rendered, "Data\nn UInt64 | s Array(String)\n---------+----------------\n1        | ['a']\n2        | []\n2 rows\n");
+    }
+}
@@ -1,12 +1,17 @@
 use std::{
-    collections::HashSet,
+    cmp::Reverse,
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
     str,
+    time::{Duration, SystemTime},
     vec::Vec,
 };
-use actix_web::http::{StatusCode, header::HeaderMap};
+use actix_web::http::{Method, StatusCode, header::HeaderMap};
 use bytes::Bytes;
 use log::debug;
 
+use crate::{matcher::Matcher, sql::NormalizedQuery};
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Ngrams {
@@ -16,8 +21,7 @@ pub struct Ngrams {
 }
 
 impl Ngrams {
-    pub fn new(n: usize, s: String) -> Self {
-        let src = s.trim().split_whitespace().map(str::to_string).collect::<Vec<String>>();
+    pub fn new(n: usize, src: Vec<String>) -> Self {
         let mut set: HashSet<String> = HashSet::new();
         if src.len() < n {
             set.insert(src[..].to_vec().join(" "));
@@ -32,6 +36,14 @@ impl Ngrams {
     pub fn compatibility_score(&self, other: &Self) -> u32 {
         self.set.intersection(&other.set).count().try_into().unwrap()
     }
+
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
 }
 
 
@@ -40,58 +52,651 @@ impl Ngrams {
 pub struct MiddlewareDataHttp {
     status: StatusCode,
     headers: HeaderMap,
+    /// Body chunks in the order they were received, empty for recordings without timings.
+    chunks: Vec<ResponseChunk>,
+}
+
+/// `len` bytes of a streamed body received `at` after the request was sent.
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseChunk {
+    pub at: Duration,
+    pub len: usize,
+}
+
+/// How long the upstream took to answer, measured from sending the request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResponseTiming {
+    /// Until the HTTP headers or the first native packet.
+    pub first_byte: Duration,
+    /// Until the end of the answer.
+    pub total: Duration,
 }
 
 impl MiddlewareDataHttp {
     pub fn new(status: StatusCode, headers: HeaderMap) -> Self {
-        Self { status, headers }
+        Self { status, headers, chunks: Vec::new() }
+    }
+
+    pub fn with_chunks(self, chunks: Vec<ResponseChunk>) -> Self {
+        Self { chunks, ..self }
     }
 
     pub fn split(&self) -> (StatusCode, HeaderMap) {
-        (self.status.clone(), self.headers.clone())
+        (self.status, self.headers.clone())
+    }
+
+    pub fn chunks(&self) -> &[ResponseChunk] {
+        &self.chunks
+    }
+}
+
+/// Protocol parameters a native TCP answer was recorded with, its packets depend on them.
+#[derive(Debug, Clone, Copy)]
+pub struct MiddlewareDataNative {
+    pub revision: u64,
+    /// Whether blocks of the answer are in compressed frames, answers are recorded in frames without compression.
+    pub compression: bool,
+}
+
+/// Request headers that take part in matching, everything else (user agents, query ids, ...) is ignored.
+pub const MATCHED_HEADERS: [&str; 4] = [
+    "x-clickhouse-database",
+    "x-clickhouse-format",
+    "x-clickhouse-user",
+    "content-type",
+];
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct MiddlewareDataRequest {
+    method: Method,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl MiddlewareDataRequest {
+    pub fn new(method: Method, path: String, query: Vec<(String, String)>, headers: &HeaderMap, body: Bytes) -> Self {
+        let mut selected = HeaderMap::new();
+        for (name, value) in headers.iter().filter(|(h, _)| MATCHED_HEADERS.contains(&h.as_str())) {
+            selected.append(name.clone(), value.clone());
+        }
+        Self { method, path, query, headers: selected, body }
+    }
+
+    /// Query text the way ClickHouse sees it: the `query` URL parameter followed by the request body.
+    pub fn clickhouse_query(&self) -> String {
+        let param = self.query.iter()
+            .find(|(key, _)| key == "query")
+            .map(|(_, value)| value.as_str())
+            .unwrap_or("");
+        let body = str::from_utf8(&self.body).unwrap_or("Invalid UTF-8 value");
+
+        match (param.is_empty(), body.is_empty()) {
+            (true, _) => body.to_string(),
+            (false, true) => param.to_string(),
+            (false, false) => format!("{}\n{}", param, body),
+        }
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> &[(String, String)] {
+        &self.query
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Method and path must be equal for two requests to be comparable at all.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.method == other.method && self.path == other.path
+    }
+
+    /// Number of equal query parameters and selected headers.
+    pub fn features_score(&self, other: &Self) -> u32 {
+        let query = self.query.iter().filter(|param| other.query.contains(param)).count();
+        let headers = self.headers.iter()
+            .filter(|(name, value)| other.headers.get_all(*name).any(|v| v == *value))
+            .count();
+        (query + headers).try_into().unwrap()
     }
 }
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct MiddlewareData {
+    request_str: String,
+    query: NormalizedQuery,
     request: Ngrams,
+    fingerprint: Ngrams,
+    request_meta: Option<MiddlewareDataRequest>,
     response: Bytes,
-    http: Option<MiddlewareDataHttp>
+    http: Option<MiddlewareDataHttp>,
+    native: Option<MiddlewareDataNative>,
+    timing: Option<ResponseTiming>,
+    /// When the answer was recorded, if that is known.
+    recorded_at: Option<SystemTime>,
+    /// Recorded by this process rather than loaded or written by hand, not saved to cassettes.
+    new_recording: bool,
+    key: u64,
 }
 
 impl MiddlewareData {
-    pub fn new(req: String, resp: Bytes, http: Option<MiddlewareDataHttp>) -> Self {
+    pub fn new(req: String, meta: Option<MiddlewareDataRequest>, resp: Bytes, http: Option<MiddlewareDataHttp>) -> Self {
+        let query = NormalizedQuery::new(&req);
         Self {
-            request: Ngrams::new(3, req),
+            key: exact_key(&query, meta.as_ref()),
+            request: Ngrams::new(3, query.tokens.clone()),
+            fingerprint: Ngrams::new(3, query.fingerprint.clone()),
+            query,
+            request_str: req,
+            request_meta: meta,
             response: resp,
             http,
+            native: None,
+            timing: None,
+            recorded_at: None,
+            new_recording: false,
         }
     }
+
+    pub fn with_timing(self, timing: Option<ResponseTiming>) -> Self {
+        Self { timing, ..self }
+    }
+
+    pub fn with_recorded_at(self, recorded_at: Option<SystemTime>) -> Self {
+        Self { recorded_at, ..self }
+    }
+
+    /// Marks the entry as recorded just now, with the current time.
+    pub fn recorded_now(self) -> Self {
+        Self { recorded_at: Some(SystemTime::now()), new_recording: true, ..self }
+    }
+
+    /// Same request with another answer.
+    pub fn with_response(self, response: Bytes, http: Option<MiddlewareDataHttp>) -> Self {
+        Self { response, http, ..self }
+    }
+
+    /// Native TCP answer: the server packets replied to the query `req`.
+    pub fn new_native(req: String, resp: Bytes, native: MiddlewareDataNative) -> Self {
+        Self { native: Some(native), ..Self::new(req, None, resp, None) }
+    }
+
+    pub fn request(&self) -> &str {
+        &self.request_str
+    }
+
+    pub fn request_meta(&self) -> Option<&MiddlewareDataRequest> {
+        self.request_meta.as_ref()
+    }
+
+    /// Normalized query tokens, or the query fingerprint with `mask_literals`.
+    pub fn tokens(&self, mask_literals: bool) -> &[String] {
+        if mask_literals { &self.query.fingerprint } else { &self.query.tokens }
+    }
+
+    pub fn ngrams(&self, mask_literals: bool) -> &Ngrams {
+        if mask_literals { &self.fingerprint } else { &self.request }
+    }
+
+    pub fn response(&self) -> &Bytes {
+        &self.response
+    }
+
+    pub fn http(&self) -> Option<&MiddlewareDataHttp> {
+        self.http.as_ref()
+    }
+
+    pub fn native(&self) -> Option<&MiddlewareDataNative> {
+        self.native.as_ref()
+    }
+
+    pub fn timing(&self) -> Option<ResponseTiming> {
+        self.timing
+    }
+
+    pub fn recorded_at(&self) -> Option<SystemTime> {
+        self.recorded_at
+    }
+
+    pub fn is_new_recording(&self) -> bool {
+        self.new_recording
+    }
+
+    /// HTTP requests are answered from HTTP recordings only and native queries from native ones.
+    fn answers(&self, req: &MiddlewareData) -> bool {
+        if req.native.is_some() { self.native.is_some() } else { self.http.is_some() }
+    }
+}
+
+/// Hash of normalized query tokens, method and path.
+fn exact_key(query: &NormalizedQuery, meta: Option<&MiddlewareDataRequest>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    query.tokens.hash(&mut hasher);
+    if let Some(meta) = meta {
+        meta.method.as_str().hash(&mut hasher);
+        meta.path.hash(&mut hasher);
+    }
+    hasher.finish()
 }
 
+/// What to replay when all recordings of a sequence were already consumed.
+#[derive(Debug, Clone, Copy)]
+pub enum SequencePolicy {
+    RepeatLast,
+    Error,
+    Cycle,
+}
 
-pub type Db = Vec<MiddlewareData>;
+impl str::FromStr for SequencePolicy {
+    type Err = String;
 
-pub trait Dbly {
-    fn find_best_response(&self, req: String) -> (Bytes, MiddlewareDataHttp);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repeat-last" => Ok(SequencePolicy::RepeatLast),
+            "error" => Ok(SequencePolicy::Error),
+            "cycle" => Ok(SequencePolicy::Cycle),
+            _ => Err(format!("unknown sequence policy: {}", s)),
+        }
+    }
 }
 
-impl Dbly for Db {
-    fn find_best_response(&self, req: String) -> (Bytes, MiddlewareDataHttp) {
-        let req_ngrams = Ngrams::new(3, req);
-        let mut best_score: u32 = 0;
-        let mut idx = 0;
-        
-        for (i, data) in self.iter().enumerate() {
-            let new_score = req_ngrams.compatibility_score(&data.request);
+#[derive(Debug, Clone, Default)]
+pub struct LookupStats {
+    pub lookups: u64,
+    pub fast_path_hits: u64,
+}
+
+/// Recordings in insertion order with an exact-match index and inverted n-gram indexes over them.
+#[derive(Debug, Clone, Default)]
+pub struct Db {
+    entries: Vec<MiddlewareData>,
+    exact: HashMap<u64, Vec<usize>>,
+    ngrams_index: HashMap<String, Vec<usize>>,
+    fingerprint_index: HashMap<String, Vec<usize>>,
+    /// Number of consumed recordings per exact key in sequential replay.
+    cursors: HashMap<u64, usize>,
+    /// Hello packet ClickHouse answered the last recorded native connection with.
+    server_hello: Option<Bytes>,
+    stats: LookupStats,
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: MiddlewareData) {
+        let idx = self.entries.len();
+        self.index(idx, &data);
+        self.entries.push(data);
+    }
+
+    fn index(&mut self, idx: usize, data: &MiddlewareData) {
+        self.exact.entry(data.key).or_default().push(idx);
+        for ngram in data.request.set.iter() {
+            self.ngrams_index.entry(ngram.clone()).or_default().push(idx);
+        }
+        for ngram in data.fingerprint.set.iter() {
+            self.fingerprint_index.entry(ngram.clone()).or_default().push(idx);
+        }
+    }
+
+    /// Rebuilds the indexes after entries were removed or moved, sequences start over as their recordings changed.
+    fn reindex(&mut self) {
+        self.exact.clear();
+        self.ngrams_index.clear();
+        self.fingerprint_index.clear();
+        self.cursors.clear();
+        let entries = std::mem::take(&mut self.entries);
+        for (idx, data) in entries.iter().enumerate() {
+            self.index(idx, data);
+        }
+        self.entries = entries;
+    }
+
+    /// Inserts the entry before `idx`, entries from `idx` on move up by one.
+    pub fn insert(&mut self, idx: usize, data: MiddlewareData) {
+        self.entries.insert(idx.min(self.entries.len()), data);
+        self.reindex();
+    }
+
+    /// Replaces the entry `idx`, returns the replaced one.
+    pub fn replace(&mut self, idx: usize, data: MiddlewareData) -> Option<MiddlewareData> {
+        let old = std::mem::replace(self.entries.get_mut(idx)?, data);
+        self.reindex();
+        Some(old)
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Option<MiddlewareData> {
+        if idx >= self.entries.len() {
+            return None;
+        }
+        let data = self.entries.remove(idx);
+        self.reindex();
+        Some(data)
+    }
+
+    /// Removes the entries not matching `keep`, returns how many were removed.
+    pub fn retain(&mut self, keep: impl FnMut(&MiddlewareData) -> bool) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(keep);
+        let removed = len - self.entries.len();
+        if removed > 0 {
+            self.reindex();
+        }
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, MiddlewareData> {
+        self.entries.iter()
+    }
+
+    pub fn last(&self) -> Option<&MiddlewareData> {
+        self.entries.last()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&MiddlewareData> {
+        self.entries.get(idx)
+    }
+
+    pub fn set_server_hello(&mut self, hello: Bytes) {
+        self.server_hello = Some(hello);
+    }
+
+    pub fn server_hello(&self) -> Option<&Bytes> {
+        self.server_hello.as_ref()
+    }
+
+    pub fn stats(&self) -> &LookupStats {
+        &self.stats
+    }
+
+    /// Latest entry with the same normalized query, method and path, preferring the most matching features.
+    fn find_exact(&self, req: &MiddlewareData) -> Option<usize> {
+        let candidates = self.exact.get(&req.key)?;
+        let mut best: Option<(u32, usize)> = None;
+        for &i in candidates {
+            let data = &self.entries[i];
+            if !data.answers(req) || data.query.tokens != req.query.tokens {
+                continue;
+            }
+            let features_score = match (&req.request_meta, &data.request_meta) {
+                (Some(meta), Some(data_meta)) if meta.is_comparable(data_meta) => meta.features_score(data_meta),
+                (None, None) => 0,
+                _ => continue,
+            };
+            if best.is_none_or(|(score, _)| features_score >= score) {
+                best = Some((features_score, i));
+            }
+        }
+        best.map(|(_, i)| i)
+    }
+
+    /// Entries recorded for the same request as the entry `idx`, in recorded order.
+    fn sequence(&self, idx: usize) -> Vec<usize> {
+        let data = &self.entries[idx];
+        self.exact[&data.key].iter()
+            .copied()
+            .filter(|&i| {
+                let other = &self.entries[i];
+                let same_meta = match (&data.request_meta, &other.request_meta) {
+                    (Some(meta), Some(other_meta)) => meta.is_comparable(other_meta),
+                    (None, None) => true,
+                    _ => false,
+                };
+                other.answers(data) && other.query.tokens == data.query.tokens && same_meta
+            })
+            .collect()
+    }
+
+    /// Next not yet consumed entry of the sequence `idx` belongs to, consuming it.
+    /// Returns `None` if the sequence is exhausted and the policy is `Error`.
+    pub fn consume(&mut self, idx: usize, policy: SequencePolicy) -> Option<usize> {
+        let sequence = self.sequence(idx);
+        let cursor = self.cursors.entry(self.entries[idx].key).or_default();
+        let pos = *cursor;
+        *cursor += 1;
+
+        if pos < sequence.len() {
+            return Some(sequence[pos]);
+        }
+        match policy {
+            SequencePolicy::RepeatLast => sequence.last().copied(),
+            SequencePolicy::Error => None,
+            SequencePolicy::Cycle => Some(sequence[pos % sequence.len()]),
+        }
+    }
+
+    /// Starts all sequences from their first recording again.
+    pub fn rewind(&mut self) {
+        self.cursors.clear();
+    }
+
+    /// Entries sharing at least one n-gram with the request, in insertion order.
+    fn candidates(&self, req_ngrams: &Ngrams, mask_literals: bool) -> Vec<usize> {
+        let index = if mask_literals { &self.fingerprint_index } else { &self.ngrams_index };
+        let mut candidates = req_ngrams.set.iter()
+            .flat_map(|ngram| index.get(ngram).into_iter().flatten().copied())
+            .collect::<Vec<usize>>();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Similarity search without the exact-match fast path.
+    /// Entries with another method or path are skipped, the rest are ranked by the matcher score,
+    /// then by the number of matching query parameters and headers and then by literal distance.
+    /// Entries recorded without request meta (old cassettes) are comparable with anything,
+    /// entries of the other protocol or with zero score are never returned.
+    pub fn best_match(&self, req: &MiddlewareData, matcher: &dyn Matcher, mask_literals: bool) -> Option<(usize, f64)> {
+        let candidates = if matcher.uses_ngrams_index() {
+            self.candidates(req.ngrams(mask_literals), mask_literals)
+        } else {
+            (0..self.entries.len()).collect()
+        };
+
+        let mut best_score: (f64, u32, Reverse<u32>) = (0.0, 0, Reverse(u32::MAX));
+        let mut idx = None;
+
+        for i in candidates {
+            let data = &self.entries[i];
+            if !data.answers(req) {
+                continue;
+            }
+            let features_score = match (&req.request_meta, &data.request_meta) {
+                (Some(meta), Some(data_meta)) => {
+                    if !meta.is_comparable(data_meta) {
+                        continue;
+                    }
+                    meta.features_score(data_meta)
+                }
+                _ => 0,
+            };
+            let score = matcher.score(req, data, mask_literals);
+            if score <= 0.0 {
+                continue;
+            }
+            let new_score = (score, features_score, Reverse(req.query.literal_distance(&data.query)));
             if new_score >= best_score {
                 best_score = new_score;
-                idx = i;
+                idx = Some(i);
             }
-            debug!("cmp score between '{:?}' and '{:?}' --- {:?}", &req_ngrams.src, &data.request.src, new_score);
-        };
-        
-        (self[idx].response.clone(), self[idx].http.as_ref().expect("have no http stuff").clone())
+            debug!("{} score between '{:?}' and '{:?}' --- {:?}", matcher.name(), req.tokens(mask_literals), data.tokens(mask_literals), new_score);
+        }
+
+        idx.map(|idx| (idx, best_score.0))
+    }
+}
+
+pub trait Dbly {
+    fn find_best_response(&mut self, req: &MiddlewareData, matcher: &dyn Matcher, mask_literals: bool) -> Option<(usize, f64)>;
+}
+
+impl Dbly for Db {
+    /// Exact matches are looked up in the index first and accepted if the matcher agrees,
+    /// otherwise falls back to the similarity search of `best_match`.
+    fn find_best_response(&mut self, req: &MiddlewareData, matcher: &dyn Matcher, mask_literals: bool) -> Option<(usize, f64)> {
+        self.stats.lookups += 1;
+
+        if let Some(idx) = self.find_exact(req) {
+            if matcher.score(req, &self.entries[idx], mask_literals) >= 1.0 {
+                self.stats.fast_path_hits += 1;
+                debug!("exact match for '{:?}' --- {}", &req.query.tokens, idx);
+                return Some((idx, 1.0));
+            }
+        }
+
+        self.best_match(req, matcher, mask_literals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::NgramsMatcher;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn request(method: Method, path: &str, query: &[(&str, &str)], format: &str) -> MiddlewareDataRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("x-clickhouse-format"), HeaderValue::from_str(format).unwrap());
+        headers.insert(HeaderName::from_static("user-agent"), HeaderValue::from_static("curl"));
+        let query = query.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        MiddlewareDataRequest::new(method, path.to_string(), query, &headers, Bytes::new())
+    }
+
+    fn entry(meta: MiddlewareDataRequest, answer: &'static str) -> MiddlewareData {
+        MiddlewareData::new("select 1".to_string(), Some(meta), Bytes::from_static(answer.as_bytes()), Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new())))
+    }
+
+    fn db_of(entries: Vec<MiddlewareData>) -> Db {
+        let mut db = Db::new();
+        for data in entries {
+            db.push(data);
+        }
+        db
+    }
+
+    fn find<'a>(db: &'a mut Db, query: &str, meta: Option<&MiddlewareDataRequest>, mask_literals: bool) -> Option<(&'a MiddlewareData, f64)> {
+        let req = MiddlewareData::new(query.to_string(), meta.cloned(), Bytes::new(), None);
+        let (idx, score) = db.find_best_response(&req, &NgramsMatcher::default(), mask_literals)?;
+        Some((db.get(idx).unwrap(), score))
     }
-}
\ No newline at end of file
+
+    fn answer(db: &mut Db, meta: &MiddlewareDataRequest) -> Option<Bytes> {
+        find(db, "select 1", Some(meta), false).map(|(data, _)| data.response().clone())
+    }
+
+    fn with_body(query: &[(&str, &str)], body: &'static str) -> MiddlewareDataRequest {
+        let query = query.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        MiddlewareDataRequest::new(Method::POST, "/".to_string(), query, &HeaderMap::new(), Bytes::from_static(body.as_bytes()))
+    }
+
+    #[test]
+    fn clickhouse_query_joins_param_and_body() {
+        assert_eq!(with_body(&[("query", "select 1")], "").clickhouse_query(), "select 1");
+        assert_eq!(with_body(&[("database", "x")], "select 2").clickhouse_query(), "select 2");
+        assert_eq!(with_body(&[("query", "insert into t format TSV")], "1\t2").clickhouse_query(), "insert into t format TSV\n1\t2");
+    }
+
+    #[test]
+    fn only_headers_that_take_part_in_matching_are_kept() {
+        let meta = request(Method::GET, "/", &[], "TSV");
+        assert_eq!(meta.headers().len(), 1);
+        assert!(meta.headers().contains_key("x-clickhouse-format"));
+    }
+
+    #[test]
+    fn method_and_path_must_be_equal() {
+        let mut db = db_of(vec![entry(request(Method::POST, "/", &[], "TSV"), "post")]);
+        assert_eq!(answer(&mut db, &request(Method::POST, "/", &[], "TSV")).unwrap().as_ref(), b"post");
+        assert!(answer(&mut db, &request(Method::GET, "/", &[], "TSV")).is_none());
+        assert!(answer(&mut db, &request(Method::POST, "/play", &[], "TSV")).is_none());
+    }
+
+    #[test]
+    fn params_and_headers_break_ties() {
+        let mut db = db_of(vec![
+            entry(request(Method::POST, "/", &[("database", "a")], "JSON"), "a json"),
+            entry(request(Method::POST, "/", &[("database", "b")], "TSV"), "b tsv"),
+            entry(request(Method::POST, "/", &[("database", "a")], "TSV"), "a tsv"),
+            entry(request(Method::POST, "/", &[("database", "c")], "CSV"), "c csv"),
+        ]);
+        assert_eq!(answer(&mut db, &request(Method::POST, "/", &[("database", "a")], "TSV")).unwrap().as_ref(), b"a tsv");
+        assert_eq!(answer(&mut db, &request(Method::POST, "/", &[("database", "a")], "JSON")).unwrap().as_ref(), b"a json");
+        assert_eq!(answer(&mut db, &request(Method::POST, "/", &[("database", "b")], "TSV")).unwrap().as_ref(), b"b tsv");
+    }
+
+    #[test]
+    fn masked_literals_only_break_ties() {
+        let mut db = db_of(vec![
+            MiddlewareData::new("select * from t limit 1".to_string(), None, Bytes::from_static(b"1"), Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new()))),
+            MiddlewareData::new("select * from t limit 2".to_string(), None, Bytes::from_static(b"2"), Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new()))),
+        ]);
+        let (data, score) = find(&mut db, "select * from t limit 3", None, false).unwrap();
+        assert!(score < 1.0, "{}", score);
+        assert_eq!(data.response().as_ref(), b"2");
+
+        let (data, score) = find(&mut db, "select * from t limit 3", None, true).unwrap();
+        assert_eq!(score, 1.0);
+        assert_eq!(data.response().as_ref(), b"2");
+        let (data, _) = find(&mut db, "SELECT * FROM t LIMIT 1", None, true).unwrap();
+        assert_eq!(data.response().as_ref(), b"1");
+    }
+
+    #[test]
+    fn exact_matches_take_the_fast_path() {
+        let ok = || Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new()));
+        let mut db = db_of(vec![
+            MiddlewareData::new("select count() from t".to_string(), None, Bytes::from_static(b"old"), ok()),
+            MiddlewareData::new("select count() from t".to_string(), None, Bytes::from_static(b"new"), ok()),
+            MiddlewareData::new("select count() from u".to_string(), None, Bytes::from_static(b"u"), None),
+        ]);
+
+        let (data, score) = find(&mut db, "SELECT count()\n  FROM t", None, false).unwrap();
+        assert_eq!((data.response().as_ref(), score), (&b"new"[..], 1.0));
+        assert_eq!((db.stats().lookups, db.stats().fast_path_hits), (1, 1));
+
+        // Entries without an HTTP answer are skipped, the similarity search takes over.
+        let (data, _) = find(&mut db, "select count() from u", None, false).unwrap();
+        assert_eq!(data.response().as_ref(), b"new");
+        assert_eq!((db.stats().lookups, db.stats().fast_path_hits), (2, 1));
+    }
+
+    #[test]
+    fn http_and_native_recordings_answer_their_own_protocol() {
+        let native = MiddlewareDataNative { revision: 54453, compression: false };
+        let mut db = Db::new();
+        db.push(MiddlewareData::new("select 1".to_string(), None, Bytes::from_static(b"http"), Some(MiddlewareDataHttp::new(StatusCode::OK, HeaderMap::new()))));
+        db.push(MiddlewareData::new_native("select 1".to_string(), Bytes::from_static(b"native"), native));
+
+        let http_req = MiddlewareData::new("select 1".to_string(), None, Bytes::new(), None);
+        let native_req = MiddlewareData::new_native("select 1".to_string(), Bytes::new(), native);
+        for (req, answer) in [(http_req, "http"), (native_req, "native")] {
+            let (idx, _) = db.find_best_response(&req, &NgramsMatcher::default(), false).unwrap();
+            assert_eq!(db.get(idx).unwrap().response().as_ref(), answer.as_bytes());
+        }
+    }
+}
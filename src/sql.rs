@@ -0,0 +1,277 @@
+/// Placeholder that replaces numeric and string literals in a query fingerprint.
+pub const LITERAL_PLACEHOLDER: &str = "?";
+
+/// ClickHouse keywords, sorted. They are case-insensitive and folded to upper case, other identifiers are case-sensitive.
+const KEYWORDS: [&str; 367] = [
+    "ACCESS", "ACTION", "ADD", "ADMIN", "AFTER", "ALGORITHM", "ALIAS", "ALL", "ALLOWED_LATENESS",
+    "ALTER", "AND", "ANTI", "ANY", "APPEND", "APPLY", "ARRAY", "AS", "ASC", "ASCENDING", "ASOF",
+    "ASSUME", "AST", "ASYNC", "ATTACH", "AUTO_INCREMENT", "BACKUP", "BEGIN", "BETWEEN",
+    "BIDIRECTIONAL", "BOTH", "BY", "CACHE", "CASCADE", "CASE", "CAST", "CHANGE", "CHANGED", "CHAR",
+    "CHARACTER", "CHECK", "CLEANUP", "CLEAR", "CLUSTER", "CODEC", "COLLATE", "COLUMN", "COLUMNS",
+    "COMMENT", "COMMIT", "COMPRESSION", "CONST", "CONSTRAINT", "CREATE", "CROSS", "CUBE", "CURRENT",
+    "CURRENT_USER", "DATA", "DATABASE", "DATABASES", "DATE", "DAY", "DAYS", "DDL", "DEDUPLICATE",
+    "DEFAULT", "DEFINER", "DELAY", "DELETE", "DEPENDS", "DESC", "DESCENDING", "DESCRIBE", "DETACH",
+    "DETACHED", "DICTIONARIES", "DICTIONARY", "DISK", "DISTINCT", "DIV", "DROP", "ELSE", "EMPTY",
+    "ENABLED", "END", "ENFORCED", "ENGINE", "EPHEMERAL", "ESTIMATE", "EVENT", "EVENTS", "EVERY",
+    "EXCEPT", "EXCHANGE", "EXISTS", "EXPLAIN", "EXPRESSION", "EXTENDED", "EXTERNAL", "FAKE",
+    "FALSE", "FETCH", "FIELDS", "FILE", "FILESYSTEM", "FILL", "FILTER", "FINAL", "FIRST",
+    "FOLLOWING", "FOR", "FOREIGN", "FORMAT", "FREEZE", "FROM", "FULL", "FULLTEXT", "FUNCTION",
+    "GLOBAL", "GRANT", "GRANTEES", "GRANTS", "GRANULARITY", "GROUP", "GROUPING", "HASH", "HAVING",
+    "HDFS", "HIERARCHICAL", "HOST", "HOUR", "HOURS", "ID", "IDENTIFIED", "IF", "IGNORE", "ILIKE",
+    "IMPLICIT", "IN", "INDEX", "INDEXES", "INDICES", "INFILE", "INHERIT", "INJECTIVE", "INNER",
+    "INSERT", "INTERPOLATE", "INTERSECT", "INTERVAL", "INTO", "INVISIBLE", "INVOKER", "IP", "IS",
+    "IS_OBJECT_ID", "JOIN", "KERBEROS", "KEY", "KEYED", "KEYS", "KILL", "KIND", "LARGE", "LAST",
+    "LAYOUT", "LDAP", "LEADING", "LEFT", "LESS", "LEVEL", "LIFETIME", "LIGHTWEIGHT", "LIKE",
+    "LIMIT", "LIMITS", "LINEAR", "LIST", "LIVE", "LOCAL", "MASK", "MATERIALIZE", "MATERIALIZED",
+    "MAX", "MEMORY", "MERGES", "METRICS", "MICROSECOND", "MICROSECONDS", "MILLISECOND",
+    "MILLISECONDS", "MIN", "MINUTE", "MINUTES", "MOD", "MODIFY", "MONTH", "MONTHS", "MOVE",
+    "MUTATION", "NAME", "NAMED", "NANOSECOND", "NANOSECONDS", "NEXT", "NO", "NONE", "NOT", "NULL",
+    "NULLS", "OBJECT", "OFFSET", "ON", "ONLY", "OPTIMIZE", "OPTION", "OR", "ORDER", "OUTER",
+    "OUTFILE", "OVER", "OVERRIDE", "PART", "PARTIAL", "PARTITION", "PARTITIONS",
+    "PART_MOVE_TO_SHARD", "PASTE", "PERIODIC", "PERMANENTLY", "PERMISSIVE", "PERSISTENT",
+    "PIPELINE", "PLAINTEXT_PASSWORD", "PLAN", "POPULATE", "PRECEDING", "PRECISION", "PREWHERE",
+    "PRIMARY", "PRIVILEGES", "PROCESSLIST", "PROFILE", "PROJECTION", "PROTOBUF", "PULL", "QUALIFY",
+    "QUARTER", "QUARTERS", "QUERY", "QUOTA", "RANDOMIZE", "RANDOMIZED", "RANGE", "READONLY",
+    "REALM", "RECOMPRESS", "RECURSIVE", "REFERENCES", "REFRESH", "REGEXP", "REMOVE", "RENAME",
+    "RESET", "RESPECT", "RESTORE", "RESTRICT", "RESTRICTIVE", "RESUME", "REVOKE", "RIGHT", "ROLE",
+    "ROLES", "ROLLBACK", "ROLLUP", "ROW", "ROWS", "SALT", "SAMPLE", "SAN", "SCHEME", "SECOND",
+    "SECONDS", "SECURITY", "SELECT", "SEMI", "SEQUENTIAL", "SERVER", "SET", "SETS", "SETTING",
+    "SETTINGS", "SHARD", "SHOW", "SIGNED", "SIMPLE", "SOURCE", "SPATIAL", "SQL", "START",
+    "STATISTICS", "STDOUT", "STEP", "STORAGE", "STRICT", "STRICTLY_ASCENDING", "SUBPARTITION",
+    "SUBPARTITIONS", "SUSPEND", "SYNC", "SYNTAX", "SYSTEM", "TABLE", "TABLES", "TAG", "TAGS",
+    "TEMPORARY", "TEST", "THAN", "THEN", "TIES", "TIME", "TIMESTAMP", "TO", "TOP", "TOTALS",
+    "TRACKING", "TRAILING", "TRANSACTION", "TREE", "TRIGGER", "TRUE", "TRUNCATE", "TTL", "TYPE",
+    "TYPEOF", "UNBOUNDED", "UNDROP", "UNFREEZE", "UNION", "UNIQUE", "UNSET", "UNSIGNED", "UNTIL",
+    "UPDATE", "URL", "USE", "USER", "USING", "UUID", "VALID", "VALUES", "VARYING", "VIEW",
+    "VISIBLE", "VOLUME", "WATCH", "WATERMARK", "WEEK", "WEEKS", "WHEN", "WHERE", "WINDOW", "WITH",
+    "WRITABLE", "YEAR", "YEARS",
+];
+
+const OPERATORS: [&str; 8] = ["<=", ">=", "!=", "<>", "==", "||", "->", "::"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    Identifier,
+    Number,
+    String,
+    Punctuation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+impl Token {
+    pub fn is_literal(&self) -> bool {
+        matches!(self.kind, TokenKind::Number | TokenKind::String)
+    }
+}
+
+/// Query split into normalized tokens, the same tokens with literals masked and the literals themselves.
+#[derive(Debug, Clone)]
+pub struct NormalizedQuery {
+    pub tokens: Vec<String>,
+    pub fingerprint: Vec<String>,
+    pub literals: Vec<String>,
+}
+
+impl NormalizedQuery {
+    pub fn new(query: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut fingerprint = Vec::new();
+        let mut literals = Vec::new();
+
+        for token in tokenize(query) {
+            if token.is_literal() {
+                fingerprint.push(LITERAL_PLACEHOLDER.to_string());
+                literals.push(token.text.clone());
+            } else {
+                fingerprint.push(token.text.clone());
+            }
+            tokens.push(token.text);
+        }
+
+        Self { tokens, fingerprint, literals }
+    }
+
+    /// Number of literals that differ between two queries, extra literals count as different.
+    pub fn literal_distance(&self, other: &Self) -> u32 {
+        let differ = self.literals.iter().zip(other.literals.iter()).filter(|(a, b)| a != b).count();
+        let extra = self.literals.len().abs_diff(other.literals.len());
+        (differ + extra).try_into().unwrap()
+    }
+}
+
+/// Splits a ClickHouse query into tokens: comments and whitespace are dropped,
+/// keywords are upper-cased, punctuation is split into separate tokens.
+pub fn tokenize(query: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while let Some(c) = query[pos..].chars().next() {
+        let rest = &query[pos..];
+
+        let (kind, end) = if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        } else if rest.starts_with("--") {
+            pos = rest.find('\n').map(|i| pos + i).unwrap_or(query.len());
+            continue;
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            pos = comment.find("*/").map(|i| pos + 2 + i + 2).unwrap_or(query.len());
+            continue;
+        } else if c == '\'' {
+            (TokenKind::String, quoted_end(query, pos, c))
+        } else if c == '"' || c == '`' {
+            (TokenKind::Identifier, quoted_end(query, pos, c))
+        } else if c.is_ascii_digit() || (c == '.' && next_is_digit(query, pos)) {
+            (TokenKind::Number, number_end(query, pos))
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map(|i| pos + i)
+                .unwrap_or(query.len());
+            (TokenKind::Identifier, end)
+        } else {
+            let len = OPERATORS.iter()
+                .find(|op| rest.starts_with(*op))
+                .map(|op| op.len())
+                .unwrap_or(c.len_utf8());
+            (TokenKind::Punctuation, pos + len)
+        };
+
+        let text = &query[pos..end];
+        let token = match kind {
+            TokenKind::Identifier if KEYWORDS.binary_search(&text.to_ascii_uppercase().as_str()).is_ok() => {
+                Token { kind: TokenKind::Keyword, text: text.to_ascii_uppercase() }
+            }
+            TokenKind::Number => Token { kind, text: text.to_ascii_lowercase() },
+            kind => Token { kind, text: text.to_string() },
+        };
+        tokens.push(token);
+        pos = end;
+    }
+
+    tokens
+}
+
+fn next_is_digit(query: &str, start: usize) -> bool {
+    query[start + 1..].starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// End of a quoted literal or identifier, backslash escapes and doubled quotes are supported.
+fn quoted_end(query: &str, start: usize, quote: char) -> usize {
+    let bytes = query.as_bytes();
+    let quote = quote as u8;
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    query.len()
+}
+
+fn number_end(query: &str, start: usize) -> usize {
+    let bytes = query.as_bytes();
+    let mut i = start;
+    if query[start..].starts_with("0x") || query[start..].starts_with("0X") {
+        i += 2;
+        while i < bytes.len() && bytes[i].is_ascii_hexdigit() {
+            i += 1;
+        }
+        return i;
+    }
+    while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+        i += 1;
+    }
+    if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+        let mut j = i + 1;
+        if j < bytes.len() && (bytes[j] == b'+' || bytes[j] == b'-') {
+            j += 1;
+        }
+        if j < bytes.len() && bytes[j].is_ascii_digit() {
+            i = j;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(query: &str) -> Vec<String> {
+        NormalizedQuery::new(query).fingerprint
+    }
+
+    #[test]
+    fn keywords_are_sorted() {
+        assert!(KEYWORDS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize("select `a b`, x::UInt8 -- comment\nfrom t /* block */ prewhere s = 'it''s' and n >= 1.5E3");
+        let kinds_and_texts = tokens.iter().map(|token| (token.kind.clone(), token.text.as_str())).collect::<Vec<_>>();
+        assert_eq!(kinds_and_texts, [
+            (TokenKind::Keyword, "SELECT"),
+            (TokenKind::Identifier, "`a b`"),
+            (TokenKind::Punctuation, ","),
+            (TokenKind::Identifier, "x"),
+            (TokenKind::Punctuation, "::"),
+            (TokenKind::Identifier, "UInt8"),
+            (TokenKind::Keyword, "FROM"),
+            (TokenKind::Identifier, "t"),
+            (TokenKind::Keyword, "PREWHERE"),
+            (TokenKind::Identifier, "s"),
+            (TokenKind::Punctuation, "="),
+            (TokenKind::String, "'it''s'"),
+            (TokenKind::Keyword, "AND"),
+            (TokenKind::Identifier, "n"),
+            (TokenKind::Punctuation, ">="),
+            (TokenKind::Number, "1.5e3"),
+        ]);
+    }
+
+    #[test]
+    fn keyword_case_and_literals_do_not_change_the_fingerprint() {
+        let lower = NormalizedQuery::new("select * from t limit 42");
+        let upper = NormalizedQuery::new("SELECT *\n  FROM t\n LIMIT 43");
+        assert_eq!(lower.fingerprint, upper.fingerprint);
+        assert_eq!(lower.fingerprint, ["SELECT", "*", "FROM", "t", "LIMIT", LITERAL_PLACEHOLDER]);
+        assert_eq!(lower.literals, ["42"]);
+        assert_eq!(lower.literal_distance(&upper), 1);
+        assert_eq!(lower.literal_distance(&NormalizedQuery::new("select * from t limit 42, 10")), 1);
+
+        let lower = NormalizedQuery::new("select * from t where id = 42");
+        let upper = NormalizedQuery::new("SELECT *\n  FROM t\n WHERE id = 43");
+        assert_eq!(lower.fingerprint, upper.fingerprint);
+        assert_eq!(lower.literal_distance(&upper), 1);
+        assert_eq!(
+            fingerprint("with x as (select 1) select case when a then b end from x window w as () settings s = 1"),
+            fingerprint("WITH x AS (SELECT 2) SELECT CASE WHEN a THEN b END FROM x WINDOW w AS () SETTINGS s = 2"),
+        );
+        assert_eq!(fingerprint("alter table t update v = 1 where true"), fingerprint("ALTER TABLE t UPDATE v = 2 WHERE TRUE"));
+    }
+
+    #[test]
+    fn identifiers_keep_their_case() {
+        assert_ne!(fingerprint("select Value from t"), fingerprint("select value from t"));
+        assert_ne!(fingerprint("select 'a' from t"), fingerprint("select `a` from t"));
+    }
+}
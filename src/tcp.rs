@@ -1,8 +1,23 @@
-use log::{error, info, debug};
-use tokio::io::{self, AsyncWriteExt};
+use bytes::{Buf, Bytes, BytesMut};
+use log::{error, info, debug, warn};
+use openssl::ssl::SslConnector;
+use std::{sync::{Arc, Mutex}, time::Duration};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant};
 
-pub async fn start_tcp_handler(local_port: &str, remote_ip: &str, remote_port: &str) -> io::Result<()> {
+use crate::{
+    appguts::{AppGuts, Lookup, Protocol, State},
+    http::MISS_EXCEPTION_CODE,
+    native::{self, ClientPacket, CompressionMethod, DecodeError, Decoded, Exception, PacketReader, ProtocolState, ServerHello, ServerPacket},
+    ngrams::{MiddlewareDataNative, ResponseTiming},
+    tls::{self, TlsConfig},
+};
+
+/// Version reported to native clients in Replay state if none was configured or recorded.
+const DEFAULT_SERVER_VERSION: &str = "23.8.1";
+
+pub async fn start_tcp_handler(local_port: &str, remote_ip: &str, remote_port: &str, tls: Option<&TlsConfig>, guts: AppGuts) -> io::Result<()> {
     debug!("start_tcp_proxy");
 
     let local_addr = format!("0.0.0.0:{}", local_port);
@@ -10,16 +25,31 @@ pub async fn start_tcp_handler(local_port: &str, remote_ip: &str, remote_port: &
     debug!("local addr: {:?}", local_addr);
     debug!("remote addr: {:?}", remote_addr);
 
+    let tls = match tls {
+        Some(tls) => Some(Arc::new((tls.acceptor()?.build(), tls.connector()?))),
+        None => None,
+    };
+
     let listener = TcpListener::bind(&local_addr).await?;
-    info!("Listening TCP on {:?}", local_addr);
+    info!("Listening {} on {:?}", if tls.is_some() { "TLS" } else { "TCP" }, local_addr);
 
     loop {
         let (socket, client_addr) = listener.accept().await?;
+        let remote_ip = remote_ip.to_owned();
         let remote_addr = remote_addr.to_owned();
+        let tls = tls.clone();
+        let guts = guts.clone();
         info!("Client {} accepted", &client_addr);
 
         tokio::spawn(async move {
-            match proxy_to_remote(socket, remote_addr.as_str()).await {
+            let result = match tls.as_deref() {
+                Some((acceptor, connector)) => match tls::accept(acceptor, socket).await {
+                    Ok(socket) => serve(socket, &remote_ip, &remote_addr, Some(connector), guts).await,
+                    Err(e) => Err(e),
+                },
+                None => serve(socket, &remote_ip, &remote_addr, None, guts).await,
+            };
+            match result {
                 Ok(_) => info!("Client {} disconnected", &client_addr),
                 Err(e) => error!("{}", e),
             }
@@ -27,44 +57,373 @@ pub async fn start_tcp_handler(local_port: &str, remote_ip: &str, remote_port: &
     }
 }
 
-async fn proxy_to_remote(mut origin: TcpStream, remote: &str) -> io::Result<()> {
+/// Proxies an accepted connection to the upstream in Record and Passthrough state, or replays it.
+/// The upstream connection is TLS-originated if a connector is given.
+async fn serve<S>(origin: S, remote_ip: &str, remote_addr: &str, connector: Option<&SslConnector>, guts: AppGuts) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let state = guts.lock().unwrap().state(Protocol::Native);
+    if matches!(state, State::Replay | State::ReplayOrRecord) {
+        return replay_native(origin, guts).await;
+    }
+
+    let remote = TcpStream::connect(remote_addr).await?;
+    match (connector, state) {
+        (Some(connector), State::Passthrough) => passthrough(origin, tls::connect(connector, remote_ip, remote).await?).await,
+        (Some(connector), _) => proxy_to_remote(origin, tls::connect(connector, remote_ip, remote).await?, guts).await,
+        (None, State::Passthrough) => passthrough(origin, remote).await,
+        (None, _) => proxy_to_remote(origin, remote, guts).await,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Query being answered with the server packets answered to it so far.
+#[derive(Debug)]
+struct PendingAnswer {
+    query: String,
+    response: BytesMut,
+    started: Instant,
+    first_byte: Option<Duration>,
+}
+
+/// Native protocol state of a proxied connection, shared by both of its directions.
+#[derive(Debug, Default)]
+struct NativeConnection {
+    protocol: ProtocolState,
+    /// Set once either side sent something we can't decode, the rest of the connection is forwarded as is.
+    raw: bool,
+    query: Option<PendingAnswer>,
+}
+
+impl NativeConnection {
+    /// Decodes the next client packet of `buf`, returns the bytes to forward and the number of bytes consumed.
+    /// The hello is re-encoded with the revision capped to the one we can decode.
+    fn client_packet(&mut self, packets: &mut PacketReader, buf: &[u8]) -> Result<(Bytes, usize), DecodeError> {
+        let (packet, len) = match packets.client(buf, &self.protocol)? {
+            Decoded::Packet(packet, len) => (packet, len),
+            Decoded::Part(len) => return Ok((Bytes::copy_from_slice(&buf[..len]), len)),
+        };
+        debug!("Client packet: {}", packet);
+
+        let out = match packet {
+            ClientPacket::Hello(mut hello) => {
+                hello.revision = hello.revision.min(native::MAX_REVISION);
+                self.protocol.revision = hello.revision;
+                let mut out = BytesMut::new();
+                hello.encode(&mut out);
+                out.freeze()
+            }
+            ClientPacket::Query(query) => {
+                self.protocol.compression = query.compression_method();
+                self.query = Some(PendingAnswer {
+                    query: query.query,
+                    response: BytesMut::new(),
+                    started: Instant::now(),
+                    first_byte: None,
+                });
+                Bytes::copy_from_slice(&buf[..len])
+            }
+            _ => Bytes::copy_from_slice(&buf[..len]),
+        };
+        Ok((out, len))
+    }
+
+    /// Same as `client_packet` for server packets, the answer to a query is recorded once it ends.
+    /// Answers are recorded with their blocks decompressed, compressed blocks keep their frames to tell where they end.
+    fn server_packet(&mut self, packets: &mut PacketReader, buf: &[u8], guts: &AppGuts) -> Result<(Bytes, usize), DecodeError> {
+        let decoded = packets.server(buf, &self.protocol)?;
+        if let Some(answer) = self.query.as_mut() {
+            answer.first_byte.get_or_insert_with(|| answer.started.elapsed());
+        }
+        let (packet, len) = match decoded {
+            Decoded::Packet(packet, len) => (packet, len),
+            Decoded::Part(len) => return Ok((Bytes::copy_from_slice(&buf[..len]), len)),
+        };
+        debug!("Server packet: {}", packet);
+
+        let out = match &packet {
+            ServerPacket::Hello(hello) => {
+                guts.lock().unwrap().record_server_hello(hello);
+                // Until now the revision is the client one, hello fields depend on it.
+                let client_revision = self.protocol.revision;
+                let mut hello = hello.clone();
+                hello.revision = hello.revision.min(native::MAX_REVISION);
+                self.protocol.revision = client_revision.min(hello.revision);
+                let mut out = BytesMut::new();
+                hello.encode(&mut out, client_revision);
+                out.freeze()
+            }
+            _ => Bytes::copy_from_slice(&buf[..len]),
+        };
+
+        if let Some(answer) = self.query.as_mut() {
+            let recorded = ProtocolState {
+                revision: self.protocol.revision,
+                compression: self.protocol.compression.map(|_| CompressionMethod::None),
+            };
+            native::encode_server_packet(&packet, &out, &recorded, &mut answer.response);
+        }
+        if packet.ends_query() {
+            if let Some(answer) = self.query.take() {
+                let native = MiddlewareDataNative {
+                    revision: self.protocol.revision,
+                    compression: self.protocol.compression.is_some(),
+                };
+                let timing = ResponseTiming {
+                    first_byte: answer.first_byte.unwrap_or_default(),
+                    total: answer.started.elapsed(),
+                };
+                guts.lock().unwrap().insert_native(answer.query, answer.response.freeze(), native, Some(timing));
+            }
+        }
+        Ok((out, len))
+    }
+}
+
+async fn proxy_to_remote<S, R>(origin: S, remote: R, guts: AppGuts) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
     debug!("proxy_to_remote");
 
-    let mut remote = TcpStream::connect(remote).await?;
+    let connection = Mutex::new(NativeConnection::default());
 
-    let (mut rc, mut wc) = origin.split();
-    let (mut rr, mut wr) = remote.split();
+    let (mut rc, mut wc) = io::split(origin);
+    let (mut rr, mut wr) = io::split(remote);
+
+    let local_to_remote = async {
+        forward_packets(&mut rc, &mut wr, &connection, Direction::ClientToServer, &guts).await?;
+        shutdown(&mut wr).await
+    };
+
+    let remote_to_local = async {
+        forward_packets(&mut rr, &mut wc, &connection, Direction::ServerToClient, &guts).await?;
+        shutdown(&mut wc).await
+    };
+
+    tokio::try_join!(local_to_remote, remote_to_local).map(|_| ())
+}
+
+/// Forwards both directions as is without decoding or recording anything.
+async fn passthrough<S, R>(origin: S, remote: R) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("passthrough");
+
+    let (mut rc, mut wc) = io::split(origin);
+    let (mut rr, mut wr) = io::split(remote);
 
     let local_to_remote = async {
         io::copy(&mut rc, &mut wr).await?;
-        // proxy_reader_writer("Client->Remote:", &mut rc, &mut wr).await?;
-        wr.shutdown().await
+        shutdown(&mut wr).await
     };
 
     let remote_to_local = async {
         io::copy(&mut rr, &mut wc).await?;
-        // proxy_reader_writer("Remote->Client:", &mut rr, &mut wc).await?;
-        wc.shutdown().await
+        shutdown(&mut wc).await
     };
 
-    tokio::try_join!(local_to_remote, remote_to_local)?;
+    tokio::try_join!(local_to_remote, remote_to_local).map(|_| ())
+}
 
-    Ok(())
+/// The peer may have closed the connection already, a TLS close_notify can't be sent to it then.
+async fn shutdown<W: AsyncWrite + Unpin>(writer: &mut W) -> io::Result<()> {
+    match writer.shutdown().await {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        result => result,
+    }
+}
+
+/// Forwards one direction of a native connection packet by packet, compressed blocks frame by frame.
+/// Decoding is only tried again once the bytes it needs have arrived.
+/// Falls back to forwarding raw bytes as soon as a packet can't be decoded.
+async fn forward_packets<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    connection: &Mutex<NativeConnection>,
+    direction: Direction,
+    guts: &AppGuts,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = BytesMut::with_capacity(8192);
+    let mut packets = PacketReader::default();
+    let mut needed = 1;
+    loop {
+        while buf.len() >= needed {
+            let out = {
+                let mut connection = connection.lock().unwrap();
+                if connection.raw {
+                    Some(buf.split().freeze())
+                } else {
+                    let decoded = match direction {
+                        Direction::ClientToServer => connection.client_packet(&mut packets, &buf),
+                        Direction::ServerToClient => connection.server_packet(&mut packets, &buf, guts),
+                    };
+                    match decoded {
+                        Ok((out, len)) => {
+                            buf.advance(len);
+                            needed = 1;
+                            Some(out)
+                        }
+                        Err(DecodeError::Incomplete(len)) => {
+                            needed = len;
+                            None
+                        }
+                        Err(e) => {
+                            warn!("{:?} native protocol {}, forwarding the rest of the connection as is", direction, e);
+                            connection.raw = true;
+                            connection.query = None;
+                            Some(buf.split().freeze())
+                        }
+                    }
+                }
+            };
+            match out {
+                Some(out) => writer.write_all(&out).await?,
+                None => break,
+            }
+        }
+
+        buf.reserve(8192);
+        if reader.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
 }
 
-// async fn proxy_reader_writer<'a, R: ?Sized, W: ?Sized>(
-//     direction: &str,
-//     reader: &'a mut R,
-//     writer: &'a mut W,
-// ) -> std::io::Result<()>
-// where
-//     R: AsyncRead + Unpin,
-//     W: AsyncWrite + Unpin,
-// {
-//     debug!("proxy_reader_writer");
-
-//     let bytes_submitted = io::copy(reader, writer).await?;
-//     info!("{} {}", direction, bytes_submitted);
-
-//     Ok(())
-// }
\ No newline at end of file
+/// Acts as a ClickHouse server without any upstream: answers the hello with the configured or recorded one,
+/// pings with pongs and every query with the packets recorded for the best matching query.
+async fn replay_native<S>(mut origin: S, guts: AppGuts) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("replay_native");
+
+    let mut protocol = ProtocolState::default();
+    let mut query: Option<(String, Instant)> = None;
+    let mut buf = BytesMut::with_capacity(8192);
+    let mut packets = PacketReader::default();
+    let mut needed = 1;
+    loop {
+        while buf.len() >= needed {
+            let (packet, len) = match packets.client(&buf, &protocol) {
+                Ok(Decoded::Packet(packet, len)) => (packet, len),
+                Ok(Decoded::Part(len)) => {
+                    buf.advance(len);
+                    needed = 1;
+                    continue;
+                }
+                Err(DecodeError::Incomplete(len)) => {
+                    needed = len;
+                    break;
+                }
+                Err(e) => {
+                    error!("Can't replay native connection, {}", e);
+                    let mut out = BytesMut::new();
+                    Exception::new(MISS_EXCEPTION_CODE, format!("network-replay-server can't decode the request, {}", e)).encode(&mut out);
+                    origin.write_all(&out).await?;
+                    return origin.shutdown().await;
+                }
+            };
+            buf.advance(len);
+            needed = 1;
+            debug!("Client packet: {}", packet);
+
+            let mut out = BytesMut::new();
+            match packet {
+                ClientPacket::Hello(hello) => {
+                    let server_hello = guts.lock().unwrap().server_hello();
+                    let mut server_hello = server_hello.unwrap_or_else(|| ServerHello::with_version(DEFAULT_SERVER_VERSION).unwrap());
+                    server_hello.revision = server_hello.revision.min(native::MAX_REVISION);
+                    protocol.revision = hello.revision.min(server_hello.revision);
+                    server_hello.encode(&mut out, protocol.revision);
+                }
+                ClientPacket::Ping => native::encode_pong(&mut out),
+                ClientPacket::Query(received) => {
+                    protocol.compression = received.compression_method();
+                    query = Some((received.query, Instant::now()));
+                }
+                // External tables are sent after the query, an empty block ends them.
+                ClientPacket::Data(data) if data.block.columns.is_empty() => {
+                    if let Some((query, started)) = query.take() {
+                        for (at, answer) in answer_query(&guts, query, &protocol) {
+                            time::sleep_until(started + at).await;
+                            origin.write_all(&answer).await?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if !out.is_empty() {
+                origin.write_all(&out).await?;
+            }
+        }
+
+        buf.reserve(8192);
+        if origin.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Answer to the query in parts to send at their times since the query was received.
+/// With simulated latency everything but the last packet is sent after the time to the first byte, the last one at the end.
+fn answer_query(guts: &AppGuts, query: String, protocol: &ProtocolState) -> Vec<(Duration, BytesMut)> {
+    let native = MiddlewareDataNative {
+        revision: protocol.revision,
+        compression: protocol.compression.is_some(),
+    };
+    let mut guts = guts.lock().unwrap();
+    let message = match guts.find_best_native_answer(query.clone(), native) {
+        Lookup::Hit(resp, recorded, pacing, score) => {
+            info!("Native replay hit for {:?} with {} score {:.2}", &query, guts.matcher().name(), score);
+            if recorded.revision != protocol.revision {
+                warn!("Answer for {:?} was recorded with revision {}, the client uses {}", &query, recorded.revision, protocol.revision);
+            }
+            // Blocks are compressed again with the method the client asked for.
+            let recorded = ProtocolState {
+                revision: recorded.revision,
+                compression: recorded.compression.then_some(CompressionMethod::Lz4),
+            };
+            // Split after transcoding, the last packet may be all that tells where a block before it ends.
+            match native::transcode_server_packets(&resp, &recorded, protocol) {
+                Ok(mut answer) => return match pacing {
+                    Some(pacing) => {
+                        debug!("Replaying {:?} in {:?} instead of {:?}", &query, pacing.simulated, pacing.recorded);
+                        match native::last_server_packet(&answer, protocol) {
+                            Ok(last) => {
+                                let end = answer.split_off(last);
+                                vec![(pacing.simulated.first_byte, answer), (pacing.simulated.total, end)]
+                            }
+                            Err(_) => vec![(pacing.simulated.total, answer)],
+                        }
+                    }
+                    None => vec![(Duration::ZERO, answer)],
+                },
+                Err(e) => {
+                    error!("Can't replay the answer recorded for {:?}, {}", &query, e);
+                    format!("network-replay-server can't replay the recorded answer, {}", e)
+                }
+            }
+        }
+        Lookup::Miss(score) => {
+            info!("Native replay miss for {:?} with best {} score {:?}", &query, guts.matcher().name(), score);
+            "No recording matches the query in network-replay-server".to_string()
+        }
+    };
+    let mut out = BytesMut::new();
+    Exception::new(MISS_EXCEPTION_CODE, message).encode(&mut out);
+    vec![(Duration::ZERO, out)]
+}
@@ -0,0 +1,138 @@
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::sync::mpsc;
+
+use crate::ngrams::ResponseChunk;
+
+/// Chunks read ahead of a slow reader of a `Tee`.
+const BUFFERED_CHUNKS: usize = 64;
+
+/// Passes the chunks of a body stream through and collects a copy of them with their timings.
+/// The stream is read in a task of its own as fast as its sender sends it, so the chunks are stamped when they arrive
+/// and a slow reader of the tee doesn't show up in the recorded pacing; up to `BUFFERED_CHUNKS` chunks wait for it,
+/// beyond that the stream is read no faster than the tee.
+/// `on_end` gets the whole body and its chunks once the stream ends, bodies that fail or whose reader is gone are dropped.
+pub struct Tee<E> {
+    receiver: mpsc::Receiver<Result<Bytes, E>>,
+}
+
+impl<E: 'static> Tee<E> {
+    /// Has to be called on an actix worker, the task reading `inner` is spawned on it.
+    pub fn new<S>(inner: S, started: Instant, on_end: impl FnOnce(Bytes, Vec<ResponseChunk>) + 'static) -> Self
+    where
+        S: Stream<Item = Result<Bytes, E>> + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        actix_web::rt::spawn(async move {
+            let mut inner = Box::pin(inner);
+            let mut body = BytesMut::new();
+            let mut chunks = Vec::new();
+            while let Some(item) = inner.next().await {
+                let failed = match &item {
+                    Ok(chunk) => {
+                        body.extend_from_slice(chunk);
+                        chunks.push(ResponseChunk { at: started.elapsed(), len: chunk.len() });
+                        false
+                    }
+                    Err(_) => true,
+                };
+                if sender.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+            on_end(body.freeze(), chunks);
+        });
+        Tee { receiver }
+    }
+}
+
+impl<E> Stream for Tee<E> {
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    type Recorded = Rc<RefCell<Option<(Bytes, Vec<ResponseChunk>)>>>;
+
+    fn recorded_tee<S: Stream<Item = Result<Bytes, ()>> + 'static>(upstream: S) -> (Tee<()>, Recorded) {
+        let recorded = Rc::new(RefCell::new(None));
+        let on_end = {
+            let recorded = recorded.clone();
+            move |body, chunks| *recorded.borrow_mut() = Some((body, chunks))
+        };
+        (Tee::new(upstream, Instant::now(), on_end), recorded)
+    }
+
+    fn upstream(delays: &'static [u64]) -> impl Stream<Item = Result<Bytes, ()>> {
+        stream::iter(delays).then(|delay| async move {
+            tokio::time::sleep(Duration::from_millis(*delay)).await;
+            Ok(Bytes::from_static(b"x"))
+        })
+    }
+
+    #[actix_web::test]
+    async fn chunks_pass_through_and_are_collected() {
+        let (tee, recorded) = recorded_tee(stream::iter([Ok(Bytes::from_static(b"a\t")), Ok(Bytes::from_static(b"1\n"))]));
+        let chunks = tee.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(chunks, ["a\t", "1\n"]);
+        let (body, chunks) = recorded.borrow_mut().take().unwrap();
+        assert_eq!(body.as_ref(), b"a\t1\n");
+        assert_eq!(chunks.iter().map(|chunk| chunk.len).collect::<Vec<_>>(), [2, 2]);
+        assert!(chunks[0].at <= chunks[1].at);
+    }
+
+    #[actix_web::test]
+    async fn chunks_are_stamped_on_arrival() {
+        let (mut tee, recorded) = recorded_tee(upstream(&[0, 50]));
+
+        // The client only starts reading after upstream is done.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut body = Vec::new();
+        while let Some(chunk) = tee.next().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(body, b"xx");
+
+        let (recorded_body, chunks) = recorded.borrow_mut().take().unwrap();
+        assert_eq!(recorded_body.as_ref(), b"xx");
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].at < Duration::from_millis(40), "{:?}", chunks);
+        assert!(chunks[1].at >= Duration::from_millis(50) && chunks[1].at < Duration::from_millis(250), "{:?}", chunks);
+    }
+
+    #[actix_web::test]
+    async fn failed_bodies_and_gone_clients_are_dropped() {
+        let (tee, recorded) = recorded_tee(stream::iter([Ok(Bytes::from_static(b"a")), Err(())]));
+        assert_eq!(tee.collect::<Vec<_>>().await.len(), 2);
+        assert!(recorded.borrow().is_none());
+
+        let (tee, recorded) = recorded_tee(upstream(&[0, 20]));
+        drop(tee);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(recorded.borrow().is_none());
+    }
+
+    #[actix_web::test]
+    async fn slow_reader_holds_the_stream_back() {
+        let chunks = (0..=BUFFERED_CHUNKS).map(|_| Ok(Bytes::from_static(b"x"))).collect::<Vec<_>>();
+        let (tee, recorded) = recorded_tee(stream::iter(chunks));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(recorded.borrow().is_none());
+
+        assert_eq!(tee.collect::<Vec<_>>().await.len(), BUFFERED_CHUNKS + 1);
+        assert_eq!(recorded.borrow_mut().take().unwrap().1.len(), BUFFERED_CHUNKS + 1);
+    }
+}
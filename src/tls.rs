@@ -0,0 +1,376 @@
+use log::{info, warn};
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslConnector, SslFiletype, SslMethod, SslVerifyMode},
+    x509::{
+        extension::{AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier},
+        X509, X509Builder, X509Name, X509NameBuilder,
+    },
+};
+use std::{fs, io::{Read, Write}, net::IpAddr, path::{Path, PathBuf}, pin::Pin};
+use tokio::{io, net::TcpStream};
+use tokio_openssl::SslStream;
+
+/// Names the generated certificate is always valid for.
+pub const DEFAULT_SANS: [&str; 2] = ["localhost", "127.0.0.1"];
+const CA_DIR_NAME: &str = "network-replay-server";
+const CA_FILE_NAME: &str = "ca.pem";
+
+const CA_NAME: &str = "network-replay-server local CA";
+const CA_DAYS: u32 = 3650;
+const LEAF_DAYS: u32 = 365;
+
+/// Certificate served on the secure local ports.
+#[derive(Clone)]
+pub enum Identity {
+    Files { cert: String, key: String },
+    Generated { cert: X509, ca: X509, key: PKey<Private> },
+}
+
+impl Identity {
+    /// Issues a certificate for `sans` signed by a local CA and writes the CA certificate to `ca_path` for clients to trust.
+    /// With `persist` the CA key is written next to it, and both are reused by later runs
+    /// as long as they are owned by the current user and nobody else can change them or read the key.
+    /// Without `ca_path` the CA lives for this run only and clients can only get it from the served chain.
+    pub fn generate(sans: &[String], ca_path: Option<&Path>, persist: bool) -> io::Result<Identity> {
+        let (ca, ca_key) = match ca_path {
+            Some(ca_path) if persist && ca_path.exists() && ca_path.with_extension("key").exists() => {
+                let ca = X509::from_pem(&read_owned(ca_path, 0o022)?).map_err(ssl_error)?;
+                let ca_key = PKey::private_key_from_pem(&read_owned(&ca_path.with_extension("key"), 0o077)?).map_err(ssl_error)?;
+                info!("Using local CA {:?}", ca_path);
+                (ca, ca_key)
+            }
+            Some(ca_path) => {
+                let (ca, ca_key) = generate_ca().map_err(ssl_error)?;
+                write_replacing(ca_path, &ca.to_pem().map_err(ssl_error)?, 0o644)?;
+                if persist {
+                    write_replacing(&ca_path.with_extension("key"), &ca_key.private_key_to_pem_pkcs8().map_err(ssl_error)?, 0o600)?;
+                }
+                info!("Generated local CA {:?}, trust it to connect to the secure ports", ca_path);
+                (ca, ca_key)
+            }
+            None => {
+                warn!("Generated local CA is not written anywhere, pass --tls_ca to trust it");
+                generate_ca().map_err(ssl_error)?
+            }
+        };
+
+        let (cert, key) = generate_leaf(&ca, &ca_key, sans).map_err(ssl_error)?;
+        info!("Generated certificate for {:?}", sans);
+        Ok(Identity::Generated { cert, ca, key })
+    }
+}
+
+/// Certificate for the secure local ports and trust settings for the secure upstream ports.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub identity: Identity,
+    /// CA file to verify the upstream certificate with instead of the system ones.
+    pub upstream_ca: Option<String>,
+    pub verify_upstream: bool,
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> io::Result<SslAcceptorBuilder> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(ssl_error)?;
+        match &self.identity {
+            Identity::Files { cert, key } => {
+                builder.set_certificate_chain_file(cert).map_err(ssl_error)?;
+                builder.set_private_key_file(key, SslFiletype::PEM).map_err(ssl_error)?;
+            }
+            Identity::Generated { cert, ca, key } => {
+                builder.set_certificate(cert).map_err(ssl_error)?;
+                builder.add_extra_chain_cert(ca.clone()).map_err(ssl_error)?;
+                builder.set_private_key(key).map_err(ssl_error)?;
+            }
+        }
+        builder.check_private_key().map_err(ssl_error)?;
+        Ok(builder)
+    }
+
+    pub fn connector(&self) -> io::Result<SslConnector> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(ssl_error)?;
+        if let Some(ca) = &self.upstream_ca {
+            builder.set_ca_file(ca).map_err(ssl_error)?;
+        }
+        if !self.verify_upstream {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        Ok(builder.build())
+    }
+}
+
+/// `network-replay-server/ca.pem` in `$XDG_DATA_HOME` or `~/.local/share`, the directory is created for the current user only.
+/// `None` when neither of them is set.
+pub fn default_ca_path() -> io::Result<Option<PathBuf>> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")));
+    let Some(data_home) = data_home else { return Ok(None) };
+    let dir = data_home.join(CA_DIR_NAME);
+
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+    check_owned(&dir, &fs::symlink_metadata(&dir)?, 0o022)?;
+    Ok(Some(dir.join(CA_FILE_NAME)))
+}
+
+pub async fn accept(acceptor: &SslAcceptor, stream: TcpStream) -> io::Result<SslStream<TcpStream>> {
+    let ssl = Ssl::new(acceptor.context()).map_err(ssl_error)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(ssl_error)?;
+    Pin::new(&mut stream).accept().await.map_err(handshake_error)?;
+    Ok(stream)
+}
+
+/// `domain` is used for SNI and to verify the upstream certificate, it may be an IP address.
+pub async fn connect(connector: &SslConnector, domain: &str, stream: TcpStream) -> io::Result<SslStream<TcpStream>> {
+    let ssl = connector.configure().map_err(ssl_error)?.into_ssl(domain).map_err(ssl_error)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(ssl_error)?;
+    Pin::new(&mut stream).connect().await.map_err(handshake_error)?;
+    Ok(stream)
+}
+
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+fn common_name(name: &str) -> Result<X509Name, ErrorStack> {
+    let mut builder = X509NameBuilder::new()?;
+    builder.append_entry_by_nid(Nid::COMMONNAME, name)?;
+    Ok(builder.build())
+}
+
+/// Builder with a random serial number, valid from now on for `days`.
+fn certificate_builder(key: &PKey<Private>, subject: &X509Name, days: u32) -> Result<X509Builder, ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(subject)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    Ok(builder)
+}
+
+fn generate_ca() -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key = generate_key()?;
+    let name = common_name(CA_NAME)?;
+    let mut builder = certificate_builder(&key, &name, CA_DAYS)?;
+    builder.set_issuer_name(&name)?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+    let key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(key_id)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
+
+fn generate_leaf(ca: &X509, ca_key: &PKey<Private>, sans: &[String]) -> Result<(X509, PKey<Private>), ErrorStack> {
+    let key = generate_key()?;
+    let name = common_name(sans.first().map_or(DEFAULT_SANS[0], String::as_str))?;
+    let mut builder = certificate_builder(&key, &name, LEAF_DAYS)?;
+    builder.set_issuer_name(ca.subject_name())?;
+    builder.append_extension(BasicConstraints::new().build()?)?;
+    builder.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+    builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+    let mut alt_names = SubjectAlternativeName::new();
+    for san in sans {
+        if san.parse::<IpAddr>().is_ok() {
+            alt_names.ip(san);
+        } else {
+            alt_names.dns(san);
+        }
+    }
+    let alt_names = alt_names.build(&builder.x509v3_context(Some(ca), None))?;
+    builder.append_extension(alt_names)?;
+    let key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(Some(ca), None))?;
+    builder.append_extension(key_id)?;
+    let authority_key_id = AuthorityKeyIdentifier::new().keyid(false).build(&builder.x509v3_context(Some(ca), None))?;
+    builder.append_extension(authority_key_id)?;
+
+    builder.sign(ca_key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
+
+/// Writes a new file with `mode` next to `path` and renames it over, so a file or symlink
+/// someone else planted at `path` is replaced instead of written through.
+fn write_replacing(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let mut tmp_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "CA path has no file name"))?.to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let file = options.open(&tmp_path).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", tmp_path, e)))?;
+    let result = (&file).write_all(contents)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Reads a file without following symlinks, refusing it unless the current user owns it
+/// and none of the `forbidden_mode` permission bits are set.
+fn read_owned(path: &Path, forbidden_mode: u32) -> io::Result<Vec<u8>> {
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options.open(path).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+    check_owned(path, &file.metadata()?, forbidden_mode)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+#[cfg(unix)]
+fn check_owned(path: &Path, metadata: &fs::Metadata, forbidden_mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let refuse = |why: String| Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("refusing {:?}: {}", path, why)));
+    if metadata.file_type().is_symlink() {
+        return refuse("it is a symlink".to_string());
+    }
+    // SAFETY: geteuid has no preconditions and can't fail.
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return refuse(format!("it is owned by uid {}, not the current user", metadata.uid()));
+    }
+    if metadata.mode() & forbidden_mode != 0 {
+        return refuse(format!("its mode is {:o}, expected none of {:o}", metadata.mode() & 0o777, forbidden_mode));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_owned(_path: &Path, _metadata: &fs::Metadata, _forbidden_mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn ssl_error(e: ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+fn handshake_error(e: openssl::ssl::Error) -> io::Error {
+    e.into_io_error().unwrap_or_else(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn missing_certificate() {
+        let missing = TempPath::new("missing.pem").to_string_lossy().into_owned();
+        let config = TlsConfig {
+            identity: Identity::Files { cert: missing.clone(), key: missing.clone() },
+            upstream_ca: None,
+            verify_upstream: true,
+        };
+        assert_eq!(config.acceptor().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        assert!(config.connector().is_ok());
+        let config = TlsConfig { upstream_ca: Some(missing), ..config };
+        assert_eq!(config.connector().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+
+    fn sans() -> Vec<String> {
+        DEFAULT_SANS.iter().map(|san| san.to_string()).collect()
+    }
+
+    #[test]
+    fn persisted_ca_is_reused() {
+        let dir = TempPath::dir("ca");
+        let ca_path = dir.join(CA_FILE_NAME);
+        let Identity::Generated { cert, ca, .. } = Identity::generate(&sans(), Some(&ca_path), true).unwrap() else { unreachable!() };
+        assert_eq!(fs::read(&ca_path).unwrap(), ca.to_pem().unwrap());
+        assert_eq!(fs::metadata(ca_path.with_extension("key")).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(ca.issued(&cert) == openssl::x509::X509VerifyResult::OK);
+
+        let Identity::Generated { cert, ca: reused, .. } = Identity::generate(&sans(), Some(&ca_path), true).unwrap() else { unreachable!() };
+        assert_eq!(reused.to_pem().unwrap(), ca.to_pem().unwrap());
+        assert!(reused.issued(&cert) == openssl::x509::X509VerifyResult::OK);
+
+        // Without persisting, every run gets a fresh CA.
+        let Identity::Generated { ca: fresh, .. } = Identity::generate(&sans(), Some(&ca_path), false).unwrap() else { unreachable!() };
+        assert_ne!(fresh.to_pem().unwrap(), ca.to_pem().unwrap());
+    }
+
+    #[test]
+    fn generated_identity_is_served() {
+        let dir = TempPath::dir("ca");
+        let config = TlsConfig {
+            identity: Identity::generate(&sans(), Some(&dir.join(CA_FILE_NAME)), false).unwrap(),
+            upstream_ca: None,
+            verify_upstream: true,
+        };
+        assert!(config.acceptor().is_ok());
+
+        let config = TlsConfig { identity: Identity::generate(&sans(), None, true).unwrap(), ..config };
+        assert!(config.acceptor().is_ok());
+    }
+
+    #[test]
+    fn readable_key_is_refused() {
+        let dir = TempPath::dir("mode");
+        let ca_path = dir.join(CA_FILE_NAME);
+        Identity::generate(&sans(), Some(&ca_path), true).unwrap();
+        fs::set_permissions(ca_path.with_extension("key"), fs::Permissions::from_mode(0o644)).unwrap();
+        let e = Identity::generate(&sans(), Some(&ca_path), true).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn symlinked_key_is_refused() {
+        let dir = TempPath::dir("symlink");
+        let ca_path = dir.join(CA_FILE_NAME);
+        Identity::generate(&sans(), Some(&dir.join("real.pem")), true).unwrap();
+        fs::copy(dir.join("real.pem"), &ca_path).unwrap();
+        symlink(dir.join("real.key"), ca_path.with_extension("key")).unwrap();
+        assert!(Identity::generate(&sans(), Some(&ca_path), true).is_err());
+    }
+
+    #[test]
+    fn planted_symlink_is_replaced_not_followed() {
+        let dir = TempPath::dir("replace");
+        let target = dir.join("target");
+        fs::write(&target, b"untouched").unwrap();
+        let ca_path = dir.join(CA_FILE_NAME);
+        symlink(&target, &ca_path).unwrap();
+        Identity::generate(&sans(), Some(&ca_path), false).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"untouched");
+        assert!(!fs::symlink_metadata(&ca_path).unwrap().file_type().is_symlink());
+    }
+}